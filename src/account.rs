@@ -0,0 +1,460 @@
+use crate::{db::DbPool, errors::ApiError, utils::auth::Email};
+use actix_web::{rt, web::Bytes};
+use chrono::NaiveDateTime;
+use futures_util::{stream, Stream, TryStreamExt};
+use sqlx::{query, query_as, query_scalar};
+use std::{collections::BTreeMap, time::Duration};
+use tokio::sync::mpsc::{channel, Sender};
+
+// Columns naming the user a row belongs to
+const OWNER_COLUMNS: [&str; 2] = ["user_uuid", "actor_uuid"];
+
+// How often deleted accounts past their grace period are looked for
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Rows read at once when backfilling canonical email addresses
+const BACKFILL_BATCH_SIZE: i64 = 500;
+
+// Secrets are never exported, not even their hashes
+fn is_secret_column(column: &str) -> bool {
+    column.ends_with("_hash")
+        || matches!(column, "secret" | "totp_secret" | "nonce" | "code_verifier")
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("`{}`", identifier.replace('`', "``"))
+}
+
+// Periodically purge the accounts deleted more than the grace period ago
+pub fn spawn_purge_task(pool: DbPool, grace_days: i64) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(PURGE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            match purge_deleted_users(&pool, grace_days).await {
+                Ok(0) => (),
+                Ok(purged) => log::info!("Purged {} deleted accounts", purged),
+                Err(e) => log::error!("Failed to purge deleted accounts: {}", e),
+            }
+        }
+    });
+}
+
+// Hard delete the accounts past their grace period with every row referencing them, returns the number purged
+pub async fn purge_deleted_users(pool: &DbPool, grace_days: i64) -> Result<u64, ApiError> {
+    let users = query!(
+        "
+        SELECT uuid, email, email_canonical FROM users
+        WHERE deleted_at < CURRENT_TIMESTAMP - INTERVAL ? DAY
+        ",
+        grace_days
+    )
+    .fetch_all(pool.get_pool())
+    .await?;
+
+    if users.is_empty() {
+        return Ok(0);
+    }
+
+    // Every foreign key to the users, found in the schema so that new tables are covered too
+    let references: Vec<(String, String)> = query_as(
+        "
+        SELECT table_name, column_name FROM information_schema.key_column_usage
+        WHERE table_schema = DATABASE()
+            AND referenced_table_name = 'users' AND referenced_column_name = 'uuid'
+        ",
+    )
+    .fetch_all(pool.get_pool())
+    .await?;
+
+    let organization_references: Vec<(String, String)> = query_as(
+        "
+        SELECT table_name, column_name FROM information_schema.key_column_usage
+        WHERE table_schema = DATABASE()
+            AND referenced_table_name = 'organizations' AND referenced_column_name = 'uuid'
+        ",
+    )
+    .fetch_all(pool.get_pool())
+    .await?;
+
+    for user in &users {
+        let mut transaction = pool.get_pool().begin().await?;
+
+        // The organizations the user is the last owner of are handed over before their membership goes
+        let owned_organizations = query!(
+            "
+            SELECT organization_uuid FROM organization_members
+            WHERE user_uuid = ? AND role = 'owner' AND organization_uuid NOT IN (
+                SELECT organization_uuid FROM organization_members
+                WHERE user_uuid != ? AND role = 'owner'
+            )
+            ",
+            &user.uuid,
+            &user.uuid
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        for organization in owned_organizations {
+            let organization_uuid = &organization.organization_uuid;
+
+            // The longest standing admin takes over, or else the longest standing member
+            let successor = query!(
+                "
+                SELECT organization_members.user_uuid
+                FROM organization_members JOIN users ON users.uuid = organization_members.user_uuid
+                WHERE organization_members.organization_uuid = ? AND organization_members.user_uuid != ?
+                    AND users.deleted_at IS NULL
+                ORDER BY organization_members.role = 'admin' DESC, organization_members.created_at
+                LIMIT 1
+                ",
+                organization_uuid,
+                &user.uuid
+            )
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+            match successor {
+                Some(successor) => {
+                    query!(
+                        "
+                        UPDATE organization_members SET role = 'owner'
+                        WHERE organization_uuid = ? AND user_uuid = ?
+                        ",
+                        organization_uuid,
+                        &successor.user_uuid
+                    )
+                    .execute(&mut *transaction)
+                    .await?;
+
+                    log::info!(
+                        "Organization {} was handed over to user {}",
+                        organization_uuid,
+                        successor.user_uuid
+                    );
+                }
+                // Nobody is left to run the organization, it goes with its last owner
+                None => {
+                    for (table, column) in &organization_references {
+                        let sql = format!(
+                            "DELETE FROM {} WHERE {} = ?",
+                            quote_identifier(table),
+                            quote_identifier(column)
+                        );
+                        sqlx::query(&sql)
+                            .bind(organization_uuid)
+                            .execute(&mut *transaction)
+                            .await?;
+                    }
+
+                    query!(
+                        "DELETE FROM organizations WHERE uuid = ?",
+                        organization_uuid
+                    )
+                    .execute(&mut *transaction)
+                    .await?;
+
+                    log::info!(
+                        "Organization {} was deleted with its last member",
+                        organization_uuid
+                    );
+                }
+            }
+        }
+
+        for (table, column) in &references {
+            // Rows owned by the user go with them, rows they only acted on are kept anonymized
+            let sql = match column.as_str() {
+                "user_uuid" => format!(
+                    "DELETE FROM {} WHERE {} = ?",
+                    quote_identifier(table),
+                    quote_identifier(column)
+                ),
+                _ => format!(
+                    "UPDATE {table} SET {column} = NULL WHERE {column} = ?",
+                    table = quote_identifier(table),
+                    column = quote_identifier(column)
+                ),
+            };
+            sqlx::query(&sql)
+                .bind(&user.uuid)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        // Accounts are locked under their canonical address
+        query!(
+            "DELETE FROM sign_in_throttles WHERE scope = 'account' AND identifier = ?",
+            user.email_canonical.as_deref().unwrap_or(&user.email)
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        query!("DELETE FROM users WHERE uuid = ?", &user.uuid)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+    }
+
+    Ok(users.len() as u64)
+}
+
+// Give the accounts and pending invitations created before addresses were normalized their canonical address,
+// returns the number of accounts given one. When several accounts share it, the oldest gets it and the others
+// keep signing in with their exact address until an admin deletes or renames them.
+// Runs at every startup: once every address is normalized, it is a single lookup of the NULL canonical addresses,
+// until then rows are read in batches so that large tables are never loaded at once
+pub async fn backfill_canonical_emails(pool: &DbPool) -> Result<u64, ApiError> {
+    let mut backfilled = 0;
+
+    // Rows left without a canonical address are skipped by resuming after the last one read
+    let mut after: Option<(NaiveDateTime, String)> = None;
+    loop {
+        let (after_created_at, after_uuid) = after.clone().unzip();
+        let users = query!(
+            "
+            SELECT uuid, email, created_at FROM users
+            WHERE email_canonical IS NULL AND (? IS NULL OR (created_at, uuid) > (?, ?))
+            ORDER BY created_at, uuid LIMIT ?
+            ",
+            after_created_at,
+            after_created_at,
+            after_uuid,
+            BACKFILL_BATCH_SIZE
+        )
+        .fetch_all(pool.get_pool())
+        .await?;
+
+        let Some(last) = users.last() else {
+            break;
+        };
+        after = Some((last.created_at, last.uuid.clone()));
+
+        for user in users {
+            let email = match Email::new(&user.email, false) {
+                Ok(email) => email,
+                Err(e) => {
+                    log::warn!("User {} has an invalid email address: {}", user.uuid, e);
+                    continue;
+                }
+            };
+
+            let updated = query!(
+                "UPDATE users SET email_canonical = ? WHERE uuid = ?",
+                email.canonical(),
+                &user.uuid
+            )
+            .execute(pool.get_pool())
+            .await;
+
+            match updated {
+                Ok(_) => backfilled += 1,
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => log::warn!(
+                    "User {} shares the email address {} with an older account",
+                    user.uuid,
+                    email.canonical()
+                ),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    let mut after: Option<String> = None;
+    loop {
+        let invitations = query!(
+            "
+            SELECT uuid, email FROM organization_invitations
+            WHERE email_canonical IS NULL AND accepted_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+                AND (? IS NULL OR uuid > ?)
+            ORDER BY uuid LIMIT ?
+            ",
+            after,
+            after,
+            BACKFILL_BATCH_SIZE
+        )
+        .fetch_all(pool.get_pool())
+        .await?;
+
+        let Some(last) = invitations.last() else {
+            break;
+        };
+        after = Some(last.uuid.clone());
+
+        for invitation in invitations {
+            if let Ok(email) = Email::new(&invitation.email, false) {
+                query!(
+                    "UPDATE organization_invitations SET email_canonical = ? WHERE uuid = ?",
+                    email.canonical(),
+                    &invitation.uuid
+                )
+                .execute(pool.get_pool())
+                .await?;
+            }
+        }
+    }
+
+    Ok(backfilled)
+}
+
+// Stream every row of the user as a JSON archive, `{"user": {...}, "tables": {"<table>": [...]}}`
+pub fn export_user_data(
+    pool: DbPool,
+    user_uuid: String,
+) -> impl Stream<Item = Result<Bytes, ApiError>> {
+    let (sender, receiver) = channel(16);
+
+    rt::spawn(async move {
+        if let Err(e) = write_export(&pool, &user_uuid, &sender).await {
+            log::error!("Failed to export the data of user {}: {}", user_uuid, e);
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    })
+}
+
+async fn write_export(
+    pool: &DbPool,
+    user_uuid: &str,
+    sender: &Sender<Result<Bytes, ApiError>>,
+) -> Result<(), ApiError> {
+    let columns: Vec<(String, String)> = query_as(
+        "
+        SELECT table_name, column_name FROM information_schema.columns
+        WHERE table_schema = DATABASE()
+        ORDER BY table_name, ordinal_position
+        ",
+    )
+    .fetch_all(pool.get_pool())
+    .await?;
+
+    let mut tables: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (table, column) in columns {
+        tables.entry(table).or_default().push(column);
+    }
+
+    let send = |chunk: String| async move {
+        sender
+            .send(Ok(Bytes::from(chunk)))
+            .await
+            .map_err(|_| ApiError::InvalidInput("The export was aborted".to_string()))
+    };
+
+    let users_columns = tables.get("users").cloned().unwrap_or_default();
+    let sql = json_select_sql("users", &users_columns, &["uuid"]);
+    let user = query_scalar::<_, String>(&sql)
+        .bind(user_uuid)
+        .fetch_optional(pool.get_pool())
+        .await?
+        .unwrap_or_else(|| "null".to_string());
+    send(format!("{{\"user\":{},\"tables\":{{", user)).await?;
+
+    let owned_tables = tables.iter().filter(|(table, columns)| {
+        table.as_str() != "users"
+            && columns
+                .iter()
+                .any(|column| OWNER_COLUMNS.contains(&column.as_str()))
+    });
+
+    for (index, (table, columns)) in owned_tables.enumerate() {
+        let separator = if index == 0 { "" } else { "," };
+        send(format!("{}\"{}\":[", separator, table)).await?;
+
+        let owner_columns: Vec<&str> = columns
+            .iter()
+            .map(String::as_str)
+            .filter(|column| OWNER_COLUMNS.contains(column))
+            .collect();
+
+        let sql = json_select_sql(table, columns, &owner_columns);
+        let mut query = query_scalar::<_, String>(&sql);
+        for _ in &owner_columns {
+            query = query.bind(user_uuid);
+        }
+
+        // Rows are forwarded one by one, large tables are never held in memory
+        let mut rows = query.fetch(pool.get_pool());
+        let mut first = true;
+        while let Some(row) = rows.try_next().await? {
+            let separator = if first { "" } else { "," };
+            send(format!("{}{}", separator, row)).await?;
+            first = false;
+        }
+
+        send("]".to_string()).await?;
+    }
+
+    send("}}".to_string()).await?;
+
+    Ok(())
+}
+
+// Let the database serialize every non secret column of the rows owned by the user
+fn json_select_sql(table: &str, columns: &[String], owner_columns: &[&str]) -> String {
+    let fields = columns
+        .iter()
+        .filter(|column| !is_secret_column(column))
+        .map(|column| {
+            format!(
+                "'{}', {}",
+                column.replace('\'', "''"),
+                quote_identifier(column)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let condition = owner_columns
+        .iter()
+        .map(|column| format!("{} = ?", quote_identifier(column)))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+
+    format!(
+        "SELECT JSON_OBJECT({}) FROM {} WHERE {}",
+        fields,
+        quote_identifier(table),
+        condition
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_columns() {
+        assert!(is_secret_column("password_hash"));
+        assert!(is_secret_column("api_key_hash"));
+        assert!(is_secret_column("totp_secret"));
+        assert!(is_secret_column("secret"));
+        assert!(!is_secret_column("email"));
+        assert!(!is_secret_column("user_uuid"));
+    }
+
+    #[test]
+    fn test_json_select_sql() {
+        let columns = vec![
+            "uuid".to_string(),
+            "user_uuid".to_string(),
+            "api_key_hash".to_string(),
+            "created_at".to_string(),
+        ];
+
+        assert_eq!(
+            json_select_sql("api_keys", &columns, &["user_uuid"]),
+            "SELECT JSON_OBJECT('uuid', `uuid`, 'user_uuid', `user_uuid`, 'created_at', `created_at`) FROM `api_keys` WHERE `user_uuid` = ?"
+        );
+    }
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(quote_identifier("api_keys"), "`api_keys`");
+        assert_eq!(quote_identifier("a`b"), "`a``b`");
+    }
+}
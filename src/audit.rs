@@ -0,0 +1,67 @@
+use crate::{db::DbPool, errors::ApiError, utils::request::RequestContext};
+use serde_json::Value;
+use sqlx::query;
+use uuid::Uuid;
+
+/// An entry of the append-only audit log
+#[derive(Debug, Default)]
+pub struct AuditEntry {
+    action: &'static str,
+    actor_uuid: Option<String>,
+    api_key_uuid: Option<String>,
+    target: Option<String>,
+    diff: Option<Value>,
+}
+
+impl AuditEntry {
+    /// Start an entry for an action named `<subject>.<verb>`, e.g. `user.password_change`
+    pub fn new(action: &'static str) -> Self {
+        Self {
+            action,
+            ..Default::default()
+        }
+    }
+
+    /// The user performing the action and the API key they authenticated with
+    pub fn actor(mut self, user_uuid: &str, api_key_uuid: Option<&str>) -> Self {
+        self.actor_uuid = Some(user_uuid.to_string());
+        self.api_key_uuid = api_key_uuid.map(str::to_string);
+        self
+    }
+
+    /// What the action was performed on, e.g. `user:<uuid>`, cut to fit its column
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into().chars().take(255).collect());
+        self
+    }
+
+    /// The changed fields, secrets must never be part of it
+    pub fn diff(mut self, diff: Value) -> Self {
+        self.diff = Some(diff);
+        self
+    }
+
+    pub async fn record(self, pool: &DbPool, context: &RequestContext) -> Result<(), ApiError> {
+        let uuid = Uuid::new_v4().to_string();
+        let diff = self.diff.map(|diff| diff.to_string());
+
+        query!(
+            "
+            INSERT INTO audit_log (uuid, actor_uuid, api_key_uuid, ip, user_agent, action, target, diff)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            ",
+            uuid,
+            self.actor_uuid,
+            self.api_key_uuid,
+            context.ip,
+            context.user_agent,
+            self.action,
+            self.target,
+            diff
+        )
+        .execute(pool.get_pool())
+        .await?;
+
+        Ok(())
+    }
+}
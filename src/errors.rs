@@ -1,20 +1,68 @@
-use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    HttpResponse, ResponseError,
+};
 use serde::Serialize;
+use sqlx::mysql::MySqlDatabaseError;
 
-/// The response body for an error response
+/// Media type of the error responses
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// The response body for an error response, the problem details of RFC 7807
 #[derive(Serialize)]
 pub struct ErrorResponse {
-    /// The error message
-    pub message: String,
+    /// URI of the kind of problem, `urn:http2sql:problem:<code>`
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// Summary of the kind of problem, the same for every occurrence
+    pub title: &'static str,
+    pub status: u16,
+    /// Explanation of this occurrence
+    pub detail: String,
+    /// Path of the request, filled in by the `problem_instance` middleware
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// Stable identifier of the error, for clients to act upon instead of the detail
+    pub code: &'static str,
+    /// Every violated rule of invalid inputs
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<Violation>,
+}
+
+/// A rule an input breaks
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Violation {
+    /// JSON pointer to the offending field of the request body, e.g. `/password`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pointer: Option<String>,
+    pub detail: String,
+}
+
+impl Violation {
+    pub fn new(detail: impl Into<String>) -> Self {
+        Self {
+            pointer: None,
+            detail: detail.into(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ApiError {
     Database(sqlx::Error),
     InvalidInput(String),
+    /// Every rule an input breaks, reported at once
+    Validation(Vec<Violation>),
     ConfigError(String),
     HashError(argon2::password_hash::Error),
     Unauthorized(String),
+    Forbidden(String),
+    /// A resource the request addresses, e.g. by its path, does not exist
+    NotFound(String),
+    MailError(String),
+    OidcError(String),
+    /// The message and the number of seconds after which the request may be retried
+    TooManyRequests(String, i64),
 }
 
 impl std::fmt::Display for ApiError {
@@ -22,32 +70,221 @@ impl std::fmt::Display for ApiError {
         match self {
             Self::Database(e) => write!(f, "Database error: {}", e),
             Self::InvalidInput(e) => write!(f, "Invalid input: {}", e),
+            Self::Validation(_) => write!(f, "Invalid input: {}", self.detail()),
             Self::ConfigError(e) => write!(f, "Config error: {}", e),
             Self::HashError(e) => write!(f, "Hash error: {}", e),
             Self::Unauthorized(e) => write!(f, "Unauthorized: {}", e),
+            Self::Forbidden(e) => write!(f, "Forbidden: {}", e),
+            Self::NotFound(e) => write!(f, "Not found: {}", e),
+            Self::MailError(e) => write!(f, "Mail error: {}", e),
+            Self::OidcError(e) => write!(f, "Identity provider error: {}", e),
+            Self::TooManyRequests(e, _) => write!(f, "Too many requests: {}", e),
         }
     }
 }
 
 impl std::error::Error for ApiError {}
 
+impl ApiError {
+    /// Stable identifier of the error, also sent in the response body
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Database(e) => database_failure(e).1,
+            Self::InvalidInput(_) | Self::Validation(_) => "invalid_input",
+            Self::ConfigError(_) => "config_error",
+            Self::HashError(_) => "hash_error",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::NotFound(_) => "not_found",
+            Self::MailError(_) => "mail_error",
+            Self::OidcError(_) => "identity_provider_error",
+            Self::TooManyRequests(_, _) => "too_many_requests",
+        }
+    }
+
+    /// Point the violations of an invalid input at a field of the request body, e.g. `/new_password`
+    pub fn at(self, pointer: &str) -> Self {
+        match self {
+            Self::InvalidInput(detail) => Self::Validation(vec![Violation {
+                pointer: Some(pointer.to_string()),
+                detail,
+            }]),
+            Self::Validation(violations) => Self::Validation(
+                violations
+                    .into_iter()
+                    .map(|violation| Violation {
+                        pointer: Some(format!(
+                            "{}{}",
+                            pointer,
+                            violation.pointer.unwrap_or_default()
+                        )),
+                        ..violation
+                    })
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+
+    // Explanation of the occurrence without the category, database errors are only described
+    fn detail(&self) -> String {
+        match self {
+            Self::Database(e) => database_failure(e).2.to_string(),
+            Self::Validation(violations) => violations
+                .iter()
+                .map(|violation| violation.detail.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            Self::InvalidInput(e)
+            | Self::ConfigError(e)
+            | Self::Unauthorized(e)
+            | Self::Forbidden(e)
+            | Self::NotFound(e)
+            | Self::MailError(e)
+            | Self::OidcError(e)
+            | Self::TooManyRequests(e, _) => e.clone(),
+            Self::HashError(e) => e.to_string(),
+        }
+    }
+}
+
+// Summary of the kind of problem behind a code
+fn title(code: &str) -> &'static str {
+    match code {
+        "not_found" => "Resource not found",
+        "duplicate_key" => "Resource already exists",
+        "still_referenced" => "Resource is still referenced",
+        "missing_reference" => "Referenced resource does not exist",
+        "data_too_long" => "Value is too long",
+        "lock_timeout" => "Resource is busy",
+        "database_unavailable" => "Database is unavailable",
+        "invalid_input" => "Invalid input",
+        "config_error" => "Config error",
+        "hash_error" => "Hash error",
+        "unauthorized" => "Unauthorized",
+        "forbidden" => "Forbidden",
+        "mail_error" => "Mail error",
+        "identity_provider_error" => "Identity provider error",
+        "too_many_requests" => "Too many requests",
+        _ => "Database error",
+    }
+}
+
+// The status, code and message a database error is reported with, the raw error may leak the schema and is only logged.
+// A missing row stays a server-side failure, turning every one into a 404 would reveal which accounts exist again
+fn database_failure(e: &sqlx::Error) -> (StatusCode, &'static str, &'static str) {
+    match e {
+        sqlx::Error::PoolTimedOut => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "database_unavailable",
+            "Database is unavailable, try again later",
+        ),
+        sqlx::Error::Database(e) => mysql_failure(
+            e.try_downcast_ref::<MySqlDatabaseError>()
+                .map(|e| e.number()),
+        ),
+        _ => internal_database_failure(),
+    }
+}
+
+// See https://mariadb.com/kb/en/mariadb-error-code-reference/
+fn mysql_failure(number: Option<u16>) -> (StatusCode, &'static str, &'static str) {
+    match number {
+        // ER_DUP_ENTRY
+        Some(1062) => (
+            StatusCode::CONFLICT,
+            "duplicate_key",
+            "Resource already exists",
+        ),
+        // ER_ROW_IS_REFERENCED and ER_ROW_IS_REFERENCED_2
+        Some(1217 | 1451) => (
+            StatusCode::CONFLICT,
+            "still_referenced",
+            "Resource is still referenced by other resources",
+        ),
+        // ER_NO_REFERENCED_ROW and ER_NO_REFERENCED_ROW_2
+        Some(1216 | 1452) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "missing_reference",
+            "Referenced resource does not exist",
+        ),
+        // ER_DATA_TOO_LONG
+        Some(1406) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "data_too_long",
+            "Value is too long",
+        ),
+        // ER_LOCK_WAIT_TIMEOUT and ER_LOCK_DEADLOCK
+        Some(1205 | 1213) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "lock_timeout",
+            "Resource is busy, try again later",
+        ),
+        _ => internal_database_failure(),
+    }
+}
+
+fn internal_database_failure() -> (StatusCode, &'static str, &'static str) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "database_error",
+        "Internal database error",
+    )
+}
+
 impl ResponseError for ApiError {
     fn status_code(&self) -> StatusCode {
         match self {
-            Self::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Self::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            Self::Database(e) => database_failure(e).0,
+            Self::InvalidInput(_) | Self::Validation(_) => StatusCode::BAD_REQUEST,
             Self::ConfigError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::HashError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::MailError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::OidcError(_) => StatusCode::BAD_GATEWAY,
+            Self::TooManyRequests(_, _) => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+
+        if matches!(self, Self::Database(_)) && status.is_server_error() {
+            log::error!("{}", self);
+        }
+
+        let code = self.code();
         let error_response = ErrorResponse {
-            message: self.to_string(),
+            problem_type: format!("urn:http2sql:problem:{}", code),
+            title: title(code),
+            status: status.as_u16(),
+            detail: self.detail(),
+            instance: None,
+            code,
+            errors: match self {
+                Self::Validation(violations) => violations.clone(),
+                _ => Vec::new(),
+            },
         };
 
-        HttpResponse::build(self.status_code()).json(error_response)
+        let mut response = HttpResponse::build(status);
+
+        match self {
+            Self::TooManyRequests(_, retry_after) => {
+                response.insert_header((RETRY_AFTER, retry_after.to_string()));
+            }
+            // Lock timeouts and saturated pools usually clear up within a second
+            Self::Database(_) if status == StatusCode::SERVICE_UNAVAILABLE => {
+                response.insert_header((RETRY_AFTER, "1"));
+            }
+            _ => (),
+        }
+
+        response
+            .content_type(PROBLEM_JSON)
+            .body(serde_json::to_string(&error_response).unwrap_or_default())
     }
 }
 
@@ -75,9 +312,34 @@ impl From<base64::DecodeError> for ApiError {
     }
 }
 
+impl From<lettre::address::AddressError> for ApiError {
+    fn from(err: lettre::address::AddressError) -> Self {
+        ApiError::MailError(err.to_string())
+    }
+}
+
+impl From<lettre::error::Error> for ApiError {
+    fn from(err: lettre::error::Error) -> Self {
+        ApiError::MailError(err.to_string())
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for ApiError {
+    fn from(err: lettre::transport::smtp::Error) -> Self {
+        ApiError::MailError(err.to_string())
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(err: reqwest::Error) -> Self {
+        ApiError::OidcError(err.to_string())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_web::http::header::CONTENT_TYPE;
 
     #[test]
     fn test_error_display() {
@@ -87,6 +349,11 @@ mod tests {
         let config_error = ApiError::ConfigError("Missing environment variable".to_string());
         let hash_error = ApiError::HashError(argon2::password_hash::Error::Algorithm);
         let unauthorized = ApiError::Unauthorized("Unauthorized".to_string());
+        let forbidden = ApiError::Forbidden("Admin role required".to_string());
+        let not_found = ApiError::NotFound("Unknown signing key".to_string());
+        let mail_error = ApiError::MailError("Connection refused".to_string());
+        let oidc_error = ApiError::OidcError("Connection refused".to_string());
+        let too_many_requests = ApiError::TooManyRequests("Account is locked".to_string(), 60);
 
         assert_eq!(
             bad_request.to_string(),
@@ -99,15 +366,39 @@ mod tests {
         );
         assert_eq!(hash_error.to_string(), "Hash error: unsupported algorithm");
         assert_eq!(unauthorized.to_string(), "Unauthorized: Unauthorized");
+        assert_eq!(forbidden.to_string(), "Forbidden: Admin role required");
+        assert_eq!(not_found.to_string(), "Not found: Unknown signing key");
+        assert_eq!(mail_error.to_string(), "Mail error: Connection refused");
+        assert_eq!(
+            oidc_error.to_string(),
+            "Identity provider error: Connection refused"
+        );
+        assert_eq!(
+            too_many_requests.to_string(),
+            "Too many requests: Account is locked"
+        );
     }
 
     #[test]
     fn test_status_codes() {
         // Test each error variant's status code
+        // A row the code expected to exist is a server-side failure, missing resources are reported explicitly
         assert_eq!(
             ApiError::Database(sqlx::Error::RowNotFound).status_code(),
             StatusCode::INTERNAL_SERVER_ERROR
         );
+        assert_eq!(
+            ApiError::NotFound("Unknown signing key".to_string()).status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            ApiError::Database(sqlx::Error::PoolTimedOut).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            ApiError::Database(sqlx::Error::WorkerCrashed).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
         assert_eq!(
             ApiError::InvalidInput("Wrong input".to_string()).status_code(),
             StatusCode::BAD_REQUEST
@@ -124,5 +415,145 @@ mod tests {
             ApiError::Unauthorized("Unauthorized".to_string()).status_code(),
             StatusCode::UNAUTHORIZED
         );
+        assert_eq!(
+            ApiError::Forbidden("Admin role required".to_string()).status_code(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            ApiError::MailError("Connection refused".to_string()).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            ApiError::OidcError("Connection refused".to_string()).status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            ApiError::TooManyRequests("Account is locked".to_string(), 60).status_code(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[test]
+    fn test_mysql_error_numbers() {
+        let expected = [
+            (Some(1062), StatusCode::CONFLICT, "duplicate_key"),
+            (Some(1451), StatusCode::CONFLICT, "still_referenced"),
+            (
+                Some(1452),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "missing_reference",
+            ),
+            (
+                Some(1406),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "data_too_long",
+            ),
+            (Some(1205), StatusCode::SERVICE_UNAVAILABLE, "lock_timeout"),
+            (Some(1213), StatusCode::SERVICE_UNAVAILABLE, "lock_timeout"),
+            (
+                Some(1146),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database_error",
+            ),
+            (None, StatusCode::INTERNAL_SERVER_ERROR, "database_error"),
+        ];
+
+        for (number, status, code) in expected {
+            let (actual_status, actual_code, _) = mysql_failure(number);
+            assert_eq!((actual_status, actual_code), (status, code));
+        }
+    }
+
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(
+            ApiError::Database(sqlx::Error::RowNotFound).code(),
+            "database_error"
+        );
+        assert_eq!(
+            ApiError::NotFound("Unknown signing key".to_string()).code(),
+            "not_found"
+        );
+        assert_eq!(
+            ApiError::InvalidInput("Wrong input".to_string()).code(),
+            "invalid_input"
+        );
+        assert_eq!(
+            ApiError::TooManyRequests("Account is locked".to_string(), 60).code(),
+            "too_many_requests"
+        );
+    }
+
+    fn problem(error: ApiError) -> serde_json::Value {
+        let response = error.error_response();
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+
+        let body = actix_web::body::to_bytes(response.into_body());
+        let body = futures_util::FutureExt::now_or_never(body)
+            .unwrap()
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_database_error_response_hides_the_raw_error() {
+        assert_eq!(
+            problem(ApiError::Database(sqlx::Error::RowNotFound)),
+            serde_json::json!({
+                "type": "urn:http2sql:problem:database_error",
+                "title": "Database error",
+                "status": 500,
+                "detail": "Internal database error",
+                "code": "database_error",
+            })
+        );
+    }
+
+    #[test]
+    fn test_validation_reports_every_violation() {
+        let error = ApiError::Validation(vec![
+            Violation::new("Password must contain at least one digit"),
+            Violation::new("Password must contain at least one special character"),
+        ])
+        .at("/new_password");
+
+        assert_eq!(
+            problem(error),
+            serde_json::json!({
+                "type": "urn:http2sql:problem:invalid_input",
+                "title": "Invalid input",
+                "status": 400,
+                "detail": "Password must contain at least one digit, Password must contain at least one special character",
+                "code": "invalid_input",
+                "errors": [
+                    {
+                        "pointer": "/new_password",
+                        "detail": "Password must contain at least one digit",
+                    },
+                    {
+                        "pointer": "/new_password",
+                        "detail": "Password must contain at least one special character",
+                    },
+                ],
+            })
+        );
+
+        // Single invalid inputs can be pointed at a field too, other errors are left alone
+        assert!(matches!(
+            ApiError::InvalidInput("Invalid email".to_string()).at("/email"),
+            ApiError::Validation(violations) if violations[0].pointer.as_deref() == Some("/email")
+        ));
+        assert!(matches!(
+            ApiError::Forbidden("Admin role required".to_string()).at("/email"),
+            ApiError::Forbidden(_)
+        ));
+    }
+
+    #[test]
+    fn test_retry_after_header() {
+        let response =
+            ApiError::TooManyRequests("Account is locked".to_string(), 60).error_response();
+
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "60");
     }
 }
@@ -1,8 +1,13 @@
+mod audit;
 mod errors;
-mod middleware;
 mod responses;
-mod utils;
 
+pub mod account;
 pub mod config;
 pub mod db;
+pub mod mail;
+pub mod middleware;
+pub mod oidc;
 pub mod routes;
+pub mod tls;
+pub mod utils;
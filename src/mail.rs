@@ -0,0 +1,147 @@
+use crate::{
+    config::{MailConfig, MailTransportConfig},
+    errors::ApiError,
+};
+use actix_web::web::block;
+use lettre::{message::Message, SmtpTransport, Transport};
+use std::{fs, path::PathBuf, sync::Arc};
+use uuid::Uuid;
+
+/// A plain text mail
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// A way of delivering mails, implementations are blocking and run on the blocking thread pool
+pub trait MailTransport: Send + Sync {
+    fn send(&self, from: &str, mail: &Mail) -> Result<(), ApiError>;
+}
+
+/// Delivers mails through an SMTP relay
+pub struct SmtpMailTransport {
+    transport: SmtpTransport,
+}
+
+impl SmtpMailTransport {
+    pub fn new(url: &str) -> Result<Self, ApiError> {
+        let transport = SmtpTransport::from_url(url)
+            .map_err(|e| ApiError::ConfigError(format!("Invalid SMTP URL: {}", e)))?
+            .build();
+
+        Ok(Self { transport })
+    }
+}
+
+impl MailTransport for SmtpMailTransport {
+    fn send(&self, from: &str, mail: &Mail) -> Result<(), ApiError> {
+        let message = Message::builder()
+            .from(from.parse()?)
+            .to(mail.to.parse()?)
+            .subject(&mail.subject)
+            .body(mail.body.clone())?;
+
+        self.transport.send(&message)?;
+
+        Ok(())
+    }
+}
+
+/// Writes each mail as an `.eml` file in a local directory
+pub struct DirectoryMailTransport {
+    path: PathBuf,
+}
+
+impl DirectoryMailTransport {
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self, ApiError> {
+        let path = path.into();
+        fs::create_dir_all(&path).map_err(|e| ApiError::MailError(e.to_string()))?;
+
+        Ok(Self { path })
+    }
+}
+
+impl MailTransport for DirectoryMailTransport {
+    fn send(&self, from: &str, mail: &Mail) -> Result<(), ApiError> {
+        let content = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            from, mail.to, mail.subject, mail.body
+        );
+
+        let file_name = format!("{}.eml", Uuid::new_v4());
+        fs::write(self.path.join(file_name), content)
+            .map_err(|e| ApiError::MailError(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    from: String,
+    transport: Arc<dyn MailTransport>,
+}
+
+impl Mailer {
+    pub fn new(config: &MailConfig) -> Result<Self, ApiError> {
+        let transport: Arc<dyn MailTransport> = match &config.transport {
+            MailTransportConfig::Smtp { url } => Arc::new(SmtpMailTransport::new(url)?),
+            MailTransportConfig::Directory { path } => Arc::new(DirectoryMailTransport::new(path)?),
+        };
+
+        Ok(Self {
+            from: config.from.clone(),
+            transport,
+        })
+    }
+
+    pub async fn send(&self, mail: Mail) -> Result<(), ApiError> {
+        let from = self.from.clone();
+        let transport = self.transport.clone();
+
+        block(move || transport.send(&from, &mail))
+            .await
+            .map_err(|e| ApiError::MailError(e.to_string()))?
+    }
+
+    // Send the mail in the background so the response does not depend on the delivery
+    pub fn send_detached(&self, mail: Mail) {
+        let mailer = self.clone();
+
+        actix_web::rt::spawn(async move {
+            if let Err(e) = mailer.send(mail).await {
+                log::error!("{}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directory_transport_writes_mail() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let transport = DirectoryMailTransport::new(&path).unwrap();
+
+        let mail = Mail {
+            to: "john.doe@gmail.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "World".to_string(),
+        };
+        transport.send("http2sql@localhost", &mail).unwrap();
+
+        let files: Vec<_> = fs::read_dir(&path).unwrap().collect();
+        assert_eq!(files.len(), 1);
+
+        let content = fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(content.contains("To: john.doe@gmail.com"));
+        assert!(content.contains("Subject: Hello"));
+        assert!(content.ends_with("World\r\n"));
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
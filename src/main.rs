@@ -1,31 +1,104 @@
 use actix_web::{
-    middleware::{Compress, Logger},
+    middleware::{from_fn, Compress, Logger},
     web::{scope, Data},
     App, HttpServer,
 };
 use env_logger::{init_from_env, Env};
-use http2sql::{config::Config, db::DbPool, routes::v1_routes};
-use std::io::{Error, ErrorKind, Result};
+use http2sql::{
+    account::{backfill_canonical_emails, spawn_purge_task},
+    config::{Config, TokenMode},
+    db::DbPool,
+    mail::Mailer,
+    middleware::{
+        api_key_cache::{spawn_last_used_flush_task, ApiKeyCache},
+        problem::problem_instance,
+        rate_limit::{rate_limit, RateLimiter},
+        request_signature::{verify_request_signature, NonceCache},
+    },
+    oidc::OidcClient,
+    routes::v1_routes,
+    tls::{on_connect, server_config},
+    utils::auth::JwtKeys,
+};
+use std::io::{Error, Result};
 
 #[actix_web::main]
 async fn main() -> Result<()> {
     init_from_env(Env::new().default_filter_or("info"));
 
-    let config = Config::build().map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let config = Config::build().map_err(Error::other)?;
+
+    let pool = DbPool::new(config.database_url.clone())
+        .await
+        .map_err(Error::other)?;
 
-    let pool = DbPool::new(config.database_url)
+    // Accounts created before email addresses were normalized are found by their canonical address from now on
+    let backfilled = backfill_canonical_emails(&pool)
         .await
-        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        .map_err(Error::other)?;
+    if backfilled > 0 {
+        log::info!("Normalized the email address of {} accounts", backfilled);
+    }
+
+    spawn_purge_task(pool.clone(), config.account_deletion_grace_days);
+
+    let mailer = Mailer::new(&config.mail).map_err(Error::other)?;
+
+    let config_data = Data::new(config.clone());
 
-    HttpServer::new(move || {
+    // Created once so that all workers share the same buckets
+    let rate_limiter = Data::new(RateLimiter::new(&config.rate_limit));
+    let api_key_cache = Data::new(ApiKeyCache::new(&config.auth.api_key_cache));
+    let nonce_cache = Data::new(NonceCache::new(config.auth.signature_replay_window_seconds));
+    let oidc_client = Data::new(OidcClient::new().map_err(Error::other)?);
+
+    // Parsed once rather than on every request carrying an access token
+    let jwt_keys = match &config.auth.token_mode {
+        TokenMode::Jwt(jwt_config) => {
+            Some(Data::new(JwtKeys::new(jwt_config).map_err(Error::other)?))
+        }
+        TokenMode::ApiKey => None,
+    };
+
+    spawn_last_used_flush_task(
+        pool.clone(),
+        api_key_cache.clone().into_inner(),
+        config.auth.api_key_cache.last_used_flush_seconds,
+    );
+
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .wrap(Compress::default())
+            .app_data(config_data.clone())
             .app_data(Data::new(pool.clone()))
-            .service(scope("/v1").configure(v1_routes))
+            .app_data(Data::new(mailer.clone()))
+            .app_data(rate_limiter.clone())
+            .app_data(api_key_cache.clone())
+            .app_data(nonce_cache.clone())
+            .app_data(oidc_client.clone())
+            .configure(|cfg| {
+                if let Some(jwt_keys) = &jwt_keys {
+                    cfg.app_data(jwt_keys.clone());
+                }
+            })
+            .service(
+                // Signatures are verified first so that signed requests are rate limited per user
+                scope("/v1")
+                    .wrap(from_fn(rate_limit))
+                    .wrap(from_fn(verify_request_signature))
+                    .wrap(from_fn(problem_instance))
+                    .configure(v1_routes),
+            )
     })
-    .bind(format!("0.0.0.0:{}", config.server_port))?
-    .workers(config.workers)
-    .run()
-    .await
+    .on_connect(on_connect)
+    .workers(config.workers);
+
+    let address = format!("0.0.0.0:{}", config.server_port);
+    let server = match &config.tls {
+        Some(tls) => server.bind_rustls_0_23(address, server_config(tls).map_err(Error::other)?)?,
+        None => server.bind(address)?,
+    };
+
+    server.run().await
 }
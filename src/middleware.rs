@@ -1 +1,6 @@
 pub mod api_key;
+pub mod api_key_cache;
+pub mod lockout;
+pub mod problem;
+pub mod rate_limit;
+pub mod request_signature;
@@ -1,39 +1,458 @@
-use crate::{db::DbPool, errors::ApiError, utils::auth::ApiKey};
-use actix_web::Result;
+use super::api_key_cache::{ApiKeyCache, CachedApiKey};
+use crate::{
+    config::{Config, TokenMode},
+    db::DbPool,
+    errors::ApiError,
+    tls::ClientCertificate,
+    utils::{
+        auth::{ApiKey, JwtKeys},
+        request::{client_ip, parse_ip_network},
+    },
+};
+use actix_web::{
+    dev::Payload,
+    http::header::{AUTHORIZATION, ORIGIN, REFERER},
+    web::Data,
+    FromRequest, HttpMessage, HttpRequest, Result,
+};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
 use chrono::Utc;
-use sqlx::{query, query_as, types::chrono::NaiveDateTime};
+use futures_util::future::LocalBoxFuture;
+use ipnet::IpNet;
+use sqlx::{
+    query, query_as,
+    types::{chrono::NaiveDateTime, Json},
+    FromRow,
+};
+use std::net::IpAddr;
+use url::Url;
 
+#[derive(FromRow)]
 struct ApiKeyMetadata {
+    uuid: String,
     user_uuid: String,
+    organization_uuid: Option<String>,
     expires_at: Option<NaiveDateTime>,
+    allowed_cidrs: Option<Json<Vec<String>>>,
+    allowed_origins: Option<Json<Vec<String>>>,
+    // The hash the key was found under
+    api_key_hash: String,
 }
 
-pub async fn auth_to_uuid(pool: &DbPool, api_key: &str) -> Result<String, ApiError> {
-    let api_key_hash = ApiKey::new(api_key)?.hash();
+// Where an API key may be used from, each empty list leaves the key unrestricted on that side
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeyRestrictions {
+    pub allowed_cidrs: Vec<IpNet>,
+    pub allowed_origins: Vec<String>,
+}
+
+impl ApiKeyRestrictions {
+    // Refuse requests from outside the allowed networks, or without an allowed `Origin` or `Referer`
+    fn check(&self, client_ip: Option<IpAddr>, origin: Option<&str>) -> Result<(), ApiError> {
+        if !self.allowed_cidrs.is_empty() {
+            let is_allowed = client_ip.is_some_and(|client_ip| {
+                self.allowed_cidrs
+                    .iter()
+                    .any(|network| network.contains(&client_ip))
+            });
+            if !is_allowed {
+                return Err(ApiError::Forbidden(
+                    "API key is not allowed from this IP address".to_string(),
+                ));
+            }
+        }
+
+        if !self.allowed_origins.is_empty() {
+            let is_allowed = origin.is_some_and(|origin| {
+                self.allowed_origins
+                    .iter()
+                    .any(|allowed_origin| allowed_origin == origin)
+            });
+            if !is_allowed {
+                return Err(ApiError::Forbidden(
+                    "API key is not allowed from this origin".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The serialized origin of a URL, e.g. `https://etl.example.com`, as browsers send it in `Origin`
+pub fn normalize_origin(value: &str) -> Option<String> {
+    let url = Url::parse(value.trim()).ok()?;
+
+    match url.scheme() {
+        "http" | "https" => Some(url.origin().ascii_serialization()),
+        _ => None,
+    }
+}
+
+// The origin of a request, from `Origin` or else from `Referer`
+fn request_origin(req: &HttpRequest) -> Option<String> {
+    [ORIGIN, REFERER].iter().find_map(|header| {
+        req.headers()
+            .get(header)
+            .and_then(|value| value.to_str().ok())
+            .and_then(normalize_origin)
+    })
+}
+
+// The authenticated user, the API key or signing key used and the organization it acts on behalf of, if any.
+// Extracting it authenticates the request
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub user_uuid: String,
+    pub api_key_uuid: Option<String>,
+    pub signing_key_uuid: Option<String>,
+    pub organization_uuid: Option<String>,
+}
+
+impl Principal {
+    // Credentials acting on behalf of an organization only reach its routes, not the account of their user
+    pub fn require_personal(&self) -> Result<(), ApiError> {
+        match self.organization_uuid {
+            Some(_) => Err(ApiError::Forbidden(
+                "Credentials act on behalf of an organization".to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromRequest for Principal {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // Signed requests and rate limited ones were already authenticated by a middleware
+        if let Some(principal) = req.extensions().get::<Principal>().cloned() {
+            return Box::pin(async move { Ok(principal) });
+        }
+
+        let req = req.clone();
+        Box::pin(async move { authenticate_request(&req).await })
+    }
+}
+
+// Authenticate a request by its bearer token or, without one, by its client certificate
+pub async fn authenticate_request(req: &HttpRequest) -> Result<Principal, ApiError> {
+    // Without credentials, a client certificate verified during the TLS handshake can stand in
+    if !req.headers().contains_key(AUTHORIZATION) {
+        if let Some(certificate) = req.conn_data::<ClientCertificate>() {
+            let pool = req.app_data::<Data<DbPool>>().ok_or_else(|| {
+                ApiError::ConfigError("Database pool is not registered".to_string())
+            })?;
+
+            return certificate_to_principal(pool, certificate).await;
+        }
+    }
+
+    let auth = BearerAuth::extract(req)
+        .await
+        .map_err(|_| ApiError::Unauthorized("Missing bearer token".to_string()))?;
+
+    let (Some(pool), Some(config), Some(cache)) = (
+        req.app_data::<Data<DbPool>>(),
+        req.app_data::<Data<Config>>(),
+        req.app_data::<Data<ApiKeyCache>>(),
+    ) else {
+        return Err(ApiError::ConfigError(
+            "Authentication state is not registered".to_string(),
+        ));
+    };
+
+    authenticate(req, pool, config, cache, auth.token()).await
+}
+
+// The principal of a request when it can be authenticated without the database: a verified signature,
+// an access token or an API key found in the cache. `None` when it cannot, or when the credentials are invalid
+pub fn authenticate_from_memory(req: &HttpRequest) -> Option<Principal> {
+    if let Some(principal) = req.extensions().get::<Principal>() {
+        return Some(principal.clone());
+    }
+
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
+    let config = req.app_data::<Data<Config>>()?;
+
+    if let TokenMode::Jwt(_) = &config.auth.token_mode {
+        if JwtKeys::looks_like_jwt(token) {
+            return Some(Principal {
+                user_uuid: req.app_data::<Data<JwtKeys>>()?.verify(token).ok()?,
+                api_key_uuid: None,
+                organization_uuid: None,
+                signing_key_uuid: None,
+            });
+        }
+    }
+
+    let cache = req.app_data::<Data<ApiKeyCache>>()?;
+    let api_key = ApiKey::new(token, &config.auth.api_key_format).ok()?;
+    let (_, current_hash) = api_key.hashes(&config.auth.api_key_hash).swap_remove(0);
+    let cached_api_key = cache.get(&current_hash)?;
+
+    check_api_key(req, cache, cached_api_key).ok()
+}
+
+// An authenticated principal with the admin role
+#[derive(Debug, Clone)]
+pub struct Admin(pub Principal);
 
-    // Get the user_uuid associated with the api_key
-    let api_key_metadata = query_as!(
-        ApiKeyMetadata,
-        "SELECT user_uuid, expires_at FROM api_keys WHERE api_key_hash = ?",
-        api_key_hash
-    )
-    .fetch_one(pool.get_pool())
-    .await?;
+impl FromRequest for Admin {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let principal = Principal::from_request(&req, payload);
+
+        Box::pin(async move {
+            let principal = principal.await?;
+
+            // Credentials shared with an organization never carry the global rights of their user
+            principal.require_personal()?;
+
+            let pool = req.app_data::<Data<DbPool>>().ok_or_else(|| {
+                ApiError::ConfigError("Database pool is not registered".to_string())
+            })?;
+
+            let user = query!("SELECT role FROM users WHERE uuid = ?", principal.user_uuid)
+                .fetch_one(pool.get_pool())
+                .await?;
+
+            if user.role != "admin" {
+                return Err(ApiError::Forbidden("Admin role required".to_string()));
+            }
+
+            Ok(Self(principal))
+        })
+    }
+}
+
+async fn authenticate(
+    req: &HttpRequest,
+    pool: &DbPool,
+    config: &Config,
+    cache: &ApiKeyCache,
+    token: &str,
+) -> Result<Principal, ApiError> {
+    // Access tokens are verified statelessly, without touching the database
+    if let TokenMode::Jwt(_) = &config.auth.token_mode {
+        if JwtKeys::looks_like_jwt(token) {
+            let jwt_keys = req.app_data::<Data<JwtKeys>>().map(Data::get_ref);
+            return Ok(Principal {
+                user_uuid: JwtKeys::registered(jwt_keys)?.verify(token)?,
+                api_key_uuid: None,
+                organization_uuid: None,
+                signing_key_uuid: None,
+            });
+        }
+    }
+
+    api_key_to_principal(req, pool, config, cache, token).await
+}
+
+async fn api_key_to_principal(
+    req: &HttpRequest,
+    pool: &DbPool,
+    config: &Config,
+    cache: &ApiKeyCache,
+    api_key: &str,
+) -> Result<Principal, ApiError> {
+    let api_key = ApiKey::new(api_key, &config.auth.api_key_format)?;
+    let hashes = api_key.hashes(&config.auth.api_key_hash);
+
+    // Cached under the current hash, only lookups that miss the cache reach the database
+    let current_hash = hashes[0].1.clone();
+    let cached_api_key = match cache.get(&current_hash) {
+        Some(cached_api_key) => cached_api_key,
+        None => {
+            let api_key_metadata = find_api_key(pool, &hashes).await?;
+
+            let cached_api_key = CachedApiKey {
+                principal: Principal {
+                    user_uuid: api_key_metadata.user_uuid,
+                    api_key_uuid: Some(api_key_metadata.uuid),
+                    organization_uuid: api_key_metadata.organization_uuid,
+                    signing_key_uuid: None,
+                },
+                expires_at: api_key_metadata.expires_at,
+                // Restrictions are validated when they are set
+                restrictions: ApiKeyRestrictions {
+                    allowed_cidrs: api_key_metadata
+                        .allowed_cidrs
+                        .map(|cidrs| {
+                            cidrs
+                                .0
+                                .iter()
+                                .filter_map(|cidr| parse_ip_network(cidr))
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                    allowed_origins: api_key_metadata
+                        .allowed_origins
+                        .map(|origins| origins.0)
+                        .unwrap_or_default(),
+                },
+            };
+            cache.insert(current_hash, cached_api_key.clone());
+
+            cached_api_key
+        }
+    };
+
+    check_api_key(req, cache, cached_api_key)
+}
+
+// Check the expiry and restrictions of a found API key
+fn check_api_key(
+    req: &HttpRequest,
+    cache: &ApiKeyCache,
+    cached_api_key: CachedApiKey,
+) -> Result<Principal, ApiError> {
     // Check if the API key has expired
-    if let Some(expires_at) = api_key_metadata.expires_at {
+    if let Some(expires_at) = cached_api_key.expires_at {
         if expires_at < Utc::now().naive_utc() {
             return Err(ApiError::Unauthorized("API key has expired".to_string()));
         }
     }
 
-    // Update the last_used_at timestamp
-    query!(
-        "UPDATE api_keys SET last_used_at = CURRENT_TIMESTAMP WHERE api_key_hash = ?",
-        api_key_hash
-    )
-    .execute(pool.get_pool())
-    .await?;
+    // Check where the API key is used from
+    let client_ip = client_ip(req).and_then(|ip| ip.parse().ok());
+    cached_api_key
+        .restrictions
+        .check(client_ip, request_origin(req).as_deref())?;
 
-    Ok(api_key_metadata.user_uuid)
+    // The last_used_at timestamp is written in batches in the background
+    if let Some(api_key_uuid) = &cached_api_key.principal.api_key_uuid {
+        cache.mark_used(api_key_uuid);
+    }
+
+    Ok(cached_api_key.principal)
+}
+
+// Look every hash the key may be stored under up at once, keys stored under an older version are rehashed
+// with the current secret
+async fn find_api_key(pool: &DbPool, hashes: &[(u8, String)]) -> Result<ApiKeyMetadata, ApiError> {
+    let (current_version, current_hash) = &hashes[0];
+
+    // The number of hashes depends on the configured secrets, hence the placeholders built at runtime
+    let sql = format!(
+        "
+        SELECT uuid, user_uuid, organization_uuid, expires_at, allowed_cidrs, allowed_origins, api_key_hash
+        FROM api_keys WHERE api_key_hash IN ({})
+        ",
+        vec!["?"; hashes.len()].join(", ")
+    );
+    let api_key_metadata = hashes
+        .iter()
+        .fold(query_as::<_, ApiKeyMetadata>(&sql), |query, (_, hash)| {
+            query.bind(hash)
+        })
+        .fetch_optional(pool.get_pool())
+        .await?
+        .ok_or_else(|| ApiError::Unauthorized("Invalid API key".to_string()))?;
+
+    if &api_key_metadata.api_key_hash != current_hash {
+        query!(
+            "
+            UPDATE api_keys SET api_key_hash = ?, api_key_hash_version = ?
+            WHERE uuid = ? AND api_key_hash = ?
+            ",
+            current_hash,
+            current_version,
+            api_key_metadata.uuid,
+            api_key_metadata.api_key_hash
+        )
+        .execute(pool.get_pool())
+        .await?;
+    }
+
+    Ok(api_key_metadata)
+}
+
+// The first identity of the certificate mapped to an active user, in the order of `identities`
+async fn certificate_to_principal(
+    pool: &DbPool,
+    certificate: &ClientCertificate,
+) -> Result<Principal, ApiError> {
+    for identity in certificate.identities()? {
+        let mapping = query!(
+            "
+            SELECT ci.user_uuid FROM certificate_identities ci
+            JOIN users u ON u.uuid = ci.user_uuid
+            WHERE ci.identity = ? AND u.deleted_at IS NULL
+            ",
+            identity
+        )
+        .fetch_optional(pool.get_pool())
+        .await?;
+
+        if let Some(mapping) = mapping {
+            return Ok(Principal {
+                user_uuid: mapping.user_uuid,
+                api_key_uuid: None,
+                organization_uuid: None,
+                signing_key_uuid: None,
+            });
+        }
+    }
+
+    Err(ApiError::Unauthorized(
+        "Client certificate is not mapped to a user".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_key_restrictions() {
+        let unrestricted = ApiKeyRestrictions::default();
+        assert!(unrestricted.check(None, None).is_ok());
+
+        let restrictions = ApiKeyRestrictions {
+            allowed_cidrs: vec![parse_ip_network("10.20.0.0/16").unwrap()],
+            allowed_origins: vec![normalize_origin("https://etl.example.com/").unwrap()],
+        };
+        let origin = Some("https://etl.example.com");
+
+        assert!(restrictions
+            .check(Some("10.20.3.4".parse().unwrap()), origin)
+            .is_ok());
+        assert!(restrictions
+            .check(Some("10.21.3.4".parse().unwrap()), origin)
+            .is_err());
+        assert!(restrictions.check(None, origin).is_err());
+        assert!(restrictions
+            .check(Some("10.20.3.4".parse().unwrap()), None)
+            .is_err());
+        assert!(restrictions
+            .check(
+                Some("10.20.3.4".parse().unwrap()),
+                Some("https://evil.example.com")
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_normalize_origin() {
+        assert_eq!(
+            normalize_origin("https://ETL.example.com/reports?x=1").as_deref(),
+            Some("https://etl.example.com")
+        );
+        assert_eq!(
+            normalize_origin("http://localhost:8080").as_deref(),
+            Some("http://localhost:8080")
+        );
+        assert!(normalize_origin("ftp://example.com").is_none());
+        assert!(normalize_origin("not an origin").is_none());
+    }
 }
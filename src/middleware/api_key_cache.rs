@@ -0,0 +1,247 @@
+use super::api_key::{ApiKeyRestrictions, Principal};
+use crate::{config::ApiKeyCacheConfig, db::DbPool, errors::ApiError};
+use actix_web::rt;
+use sqlx::types::chrono::NaiveDateTime;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Number of API keys whose `last_used_at` is written in a single statement
+const LAST_USED_BATCH_SIZE: usize = 500;
+
+// What an API key lookup resolved to
+#[derive(Debug, Clone)]
+pub struct CachedApiKey {
+    pub principal: Principal,
+    pub expires_at: Option<NaiveDateTime>,
+    pub restrictions: ApiKeyRestrictions,
+}
+
+struct Entry {
+    api_key: CachedApiKey,
+    cached_at: Instant,
+}
+
+// Bounded TTL cache of API key hash to principal, shared by all workers, plus the keys used since the last flush
+pub struct ApiKeyCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+    used_api_keys: Mutex<HashSet<String>>,
+}
+
+impl ApiKeyCache {
+    pub fn new(config: &ApiKeyCacheConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.ttl_seconds),
+            capacity: config.capacity,
+            entries: Mutex::new(HashMap::new()),
+            used_api_keys: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn get(&self, api_key_hash: &str) -> Option<CachedApiKey> {
+        self.get_at(api_key_hash, Instant::now())
+    }
+
+    fn get_at(&self, api_key_hash: &str, now: Instant) -> Option<CachedApiKey> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        entries
+            .get(api_key_hash)
+            .filter(|entry| now.duration_since(entry.cached_at) < self.ttl)
+            .map(|entry| entry.api_key.clone())
+    }
+
+    pub fn insert(&self, api_key_hash: String, api_key: CachedApiKey) {
+        self.insert_at(api_key_hash, api_key, Instant::now())
+    }
+
+    fn insert_at(&self, api_key_hash: String, api_key: CachedApiKey, now: Instant) {
+        if self.ttl.is_zero() || self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        if entries.len() >= self.capacity && !entries.contains_key(&api_key_hash) {
+            // Drop the stale entries first, then the oldest one if the cache is still full
+            entries.retain(|_, entry| now.duration_since(entry.cached_at) < self.ttl);
+
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.cached_at)
+                    .map(|(hash, _)| hash.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+
+        entries.insert(
+            api_key_hash,
+            Entry {
+                api_key,
+                cached_at: now,
+            },
+        );
+    }
+
+    // Forget every cached key of the user, must be called whenever their keys are revoked
+    pub fn invalidate_user(&self, user_uuid: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        entries.retain(|_, entry| entry.api_key.principal.user_uuid != user_uuid);
+    }
+
+    // Remember the key was used, `last_used_at` is written by the next flush
+    pub fn mark_used(&self, api_key_uuid: &str) {
+        let mut used_api_keys = self.used_api_keys.lock().unwrap_or_else(|e| e.into_inner());
+
+        used_api_keys.insert(api_key_uuid.to_string());
+    }
+
+    fn take_used(&self) -> Vec<String> {
+        let mut used_api_keys = self.used_api_keys.lock().unwrap_or_else(|e| e.into_inner());
+
+        used_api_keys.drain().collect()
+    }
+}
+
+// Periodically write the `last_used_at` of the keys used since the previous flush
+pub fn spawn_last_used_flush_task(pool: DbPool, cache: Arc<ApiKeyCache>, interval_seconds: u64) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(Duration::from_secs(interval_seconds));
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = flush_last_used(&pool, &cache).await {
+                log::error!("Failed to write the last use of API keys: {}", e);
+            }
+        }
+    });
+}
+
+// Write the `last_used_at` of the keys used since the previous flush, returns the number of keys written
+pub async fn flush_last_used(pool: &DbPool, cache: &ApiKeyCache) -> Result<u64, ApiError> {
+    let used_api_keys = cache.take_used();
+
+    for batch in used_api_keys.chunks(LAST_USED_BATCH_SIZE) {
+        let placeholders = vec!["?"; batch.len()].join(", ");
+        let sql = format!(
+            "UPDATE api_keys SET last_used_at = CURRENT_TIMESTAMP WHERE uuid IN ({})",
+            placeholders
+        );
+
+        let mut query = sqlx::query(&sql);
+        for api_key_uuid in batch {
+            query = query.bind(api_key_uuid);
+        }
+        query.execute(pool.get_pool()).await?;
+    }
+
+    Ok(used_api_keys.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl_seconds: u64, capacity: usize) -> ApiKeyCache {
+        ApiKeyCache::new(&ApiKeyCacheConfig {
+            ttl_seconds,
+            capacity,
+            last_used_flush_seconds: 10,
+        })
+    }
+
+    fn api_key(user_uuid: &str) -> CachedApiKey {
+        CachedApiKey {
+            principal: Principal {
+                user_uuid: user_uuid.to_string(),
+                api_key_uuid: Some(format!("key-of-{}", user_uuid)),
+                organization_uuid: None,
+                signing_key_uuid: None,
+            },
+            expires_at: None,
+            restrictions: ApiKeyRestrictions::default(),
+        }
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let cache = cache(30, 10);
+        let now = Instant::now();
+
+        cache.insert_at("hash".to_string(), api_key("john"), now);
+        assert!(cache
+            .get_at("hash", now + Duration::from_secs(29))
+            .is_some());
+        assert!(cache
+            .get_at("hash", now + Duration::from_secs(30))
+            .is_none());
+        assert!(cache.get_at("unknown", now).is_none());
+    }
+
+    #[test]
+    fn test_capacity_evicts_oldest() {
+        let cache = cache(30, 2);
+        let now = Instant::now();
+
+        cache.insert_at("first".to_string(), api_key("john"), now);
+        cache.insert_at(
+            "second".to_string(),
+            api_key("jane"),
+            now + Duration::from_secs(1),
+        );
+        cache.insert_at(
+            "third".to_string(),
+            api_key("alice"),
+            now + Duration::from_secs(2),
+        );
+
+        assert!(cache.get_at("first", now).is_none());
+        assert!(cache.get_at("second", now).is_some());
+        assert!(cache.get_at("third", now).is_some());
+    }
+
+    #[test]
+    fn test_invalidate_user() {
+        let cache = cache(30, 10);
+
+        cache.insert("john-1".to_string(), api_key("john"));
+        cache.insert("john-2".to_string(), api_key("john"));
+        cache.insert("jane".to_string(), api_key("jane"));
+        cache.invalidate_user("john");
+
+        assert!(cache.get("john-1").is_none());
+        assert!(cache.get("john-2").is_none());
+        assert!(cache.get("jane").is_some());
+    }
+
+    #[test]
+    fn test_zero_ttl_disables_cache() {
+        let cache = cache(0, 10);
+
+        cache.insert("hash".to_string(), api_key("john"));
+        assert!(cache.get("hash").is_none());
+    }
+
+    #[test]
+    fn test_used_keys_are_taken_once() {
+        let cache = cache(30, 10);
+
+        cache.mark_used("key-1");
+        cache.mark_used("key-1");
+        cache.mark_used("key-2");
+
+        let mut used = cache.take_used();
+        used.sort();
+        assert_eq!(used, vec!["key-1", "key-2"]);
+        assert!(cache.take_used().is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{
+    audit::AuditEntry, config::LockoutConfig, db::DbPool, errors::ApiError,
+    utils::request::RequestContext,
+};
+use actix_web::Result;
+use chrono::{Duration, Utc};
+use serde_json::json;
+use sqlx::{query, types::chrono::NaiveDateTime};
+use uuid::Uuid;
+
+// What a sign-in throttle counts the failures of
+#[derive(Debug, Clone, Copy)]
+pub enum Scope {
+    Account,
+    Ip,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Account => "account",
+            Self::Ip => "ip",
+        }
+    }
+
+    fn max_attempts(&self, lockout_config: &LockoutConfig) -> u32 {
+        match self {
+            Self::Account => lockout_config.max_attempts_per_account,
+            Self::Ip => lockout_config.max_attempts_per_ip,
+        }
+    }
+}
+
+// Reject the sign-in while the account or client IP is locked out
+pub async fn check_lockout(pool: &DbPool, scope: Scope, identifier: &str) -> Result<(), ApiError> {
+    let locked_until = query!(
+        "SELECT locked_until FROM sign_in_throttles WHERE scope = ? AND identifier = ?",
+        scope.as_str(),
+        identifier
+    )
+    .fetch_optional(pool.get_pool())
+    .await?
+    .and_then(|throttle| throttle.locked_until);
+
+    if let Some(locked_until) = locked_until {
+        let retry_after = (locked_until - Utc::now().naive_utc()).num_seconds();
+
+        if retry_after > 0 {
+            return Err(ApiError::TooManyRequests(
+                "Too many failed sign-in attempts, try again later".to_string(),
+                retry_after,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Count a failed sign-in and lock the account or client IP once it exceeds its threshold
+pub async fn record_failure(
+    pool: &DbPool,
+    context: &RequestContext,
+    lockout_config: &LockoutConfig,
+    scope: Scope,
+    identifier: &str,
+) -> Result<(), ApiError> {
+    // Failures older than a day are forgotten, the counter starts over
+    query!(
+        "
+        INSERT INTO sign_in_throttles (scope, identifier, failed_attempts, last_failure_at)
+        VALUES (?, ?, 1, CURRENT_TIMESTAMP)
+        ON DUPLICATE KEY UPDATE
+            failed_attempts = IF(last_failure_at < CURRENT_TIMESTAMP - INTERVAL 1 DAY, 1, failed_attempts + 1),
+            last_failure_at = CURRENT_TIMESTAMP
+        ",
+        scope.as_str(),
+        identifier
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    let throttle = query!(
+        "SELECT failed_attempts FROM sign_in_throttles WHERE scope = ? AND identifier = ?",
+        scope.as_str(),
+        identifier
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    let Some(lockout_seconds) = lockout_config
+        .lockout_seconds(throttle.failed_attempts, scope.max_attempts(lockout_config))
+    else {
+        return Ok(());
+    };
+
+    let locked_until = (Utc::now() + Duration::seconds(lockout_seconds)).naive_utc();
+
+    query!(
+        "UPDATE sign_in_throttles SET locked_until = ? WHERE scope = ? AND identifier = ?",
+        locked_until,
+        scope.as_str(),
+        identifier
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    log::warn!(
+        "Sign-in locked for {} {} until {} after {} failed attempts",
+        scope.as_str(),
+        identifier,
+        locked_until,
+        throttle.failed_attempts
+    );
+
+    record_event(pool, scope, identifier, "locked", Some(locked_until), None).await?;
+
+    // Lockouts are also part of the audit log, next to the failures leading to them
+    AuditEntry::new("auth.lockout")
+        .target(format!("{}:{}", scope.as_str(), identifier))
+        .diff(json!({
+            "failed_attempts": throttle.failed_attempts,
+            "locked_until": locked_until,
+        }))
+        .record(pool, context)
+        .await
+}
+
+// Forget the failures of an account after a successful sign-in
+pub async fn reset_failures(pool: &DbPool, scope: Scope, identifier: &str) -> Result<(), ApiError> {
+    query!(
+        "DELETE FROM sign_in_throttles WHERE scope = ? AND identifier = ?",
+        scope.as_str(),
+        identifier
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    Ok(())
+}
+
+// Lift a lockout on behalf of an admin, returns whether there was anything to lift
+pub async fn unlock(
+    pool: &DbPool,
+    scope: Scope,
+    identifier: &str,
+    actor_uuid: &str,
+) -> Result<bool, ApiError> {
+    let result = query!(
+        "DELETE FROM sign_in_throttles WHERE scope = ? AND identifier = ?",
+        scope.as_str(),
+        identifier
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    record_event(pool, scope, identifier, "unlocked", None, Some(actor_uuid)).await?;
+
+    Ok(true)
+}
+
+async fn record_event(
+    pool: &DbPool,
+    scope: Scope,
+    identifier: &str,
+    event: &str,
+    locked_until: Option<NaiveDateTime>,
+    actor_uuid: Option<&str>,
+) -> Result<(), ApiError> {
+    let uuid = Uuid::new_v4().to_string();
+
+    query!(
+        "
+        INSERT INTO lockout_events (uuid, scope, identifier, event, locked_until, actor_uuid)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ",
+        uuid,
+        scope.as_str(),
+        identifier,
+        event,
+        locked_until,
+        actor_uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use crate::errors::PROBLEM_JSON;
+use actix_web::{
+    body::{to_bytes, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::CONTENT_TYPE,
+    middleware::Next,
+    Error,
+};
+use serde_json::Value;
+
+// Fill in the `instance` of problem details with the request path, which errors are built without.
+// The query string is left out, it may carry secrets
+pub async fn problem_instance(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let path = req.path().to_string();
+
+    let response = next.call(req).await?.map_into_boxed_body();
+
+    let is_problem = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == PROBLEM_JSON);
+    if !is_problem {
+        return Ok(response);
+    }
+
+    let (req, response) = response.into_parts();
+    let (response, body) = response.into_parts();
+
+    let Ok(body) = to_bytes(body).await else {
+        return Ok(ServiceResponse::new(
+            req,
+            response.set_body(BoxBody::new(())),
+        ));
+    };
+
+    let body = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Object(mut problem)) => {
+            problem.insert("instance".to_string(), Value::String(path));
+            serde_json::to_vec(&problem).unwrap_or_else(|_| body.to_vec())
+        }
+        _ => body.to_vec(),
+    };
+
+    Ok(ServiceResponse::new(
+        req,
+        response.set_body(BoxBody::new(body)),
+    ))
+}
@@ -0,0 +1,332 @@
+use super::api_key::{authenticate_from_memory, authenticate_request, Principal};
+use crate::{
+    config::{Quota, RateLimitConfig},
+    errors::ApiError,
+    tls::ClientCertificate,
+    utils::request::client_ip,
+};
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION},
+    middleware::Next,
+    web::Data,
+    Error, HttpMessage, ResponseError,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Buckets kept in memory at most, further callers share a bucket of their quota until room is made
+const MAX_BUCKETS: usize = 10_000;
+
+// Shortest time between two sweeps of the full buckets, which walk all of them
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Outcome of taking a request from a bucket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again
+    pub reset_seconds: u64,
+    /// Seconds until the next request is allowed, zero when allowed
+    pub retry_after_seconds: u64,
+}
+
+// Where the token buckets are kept, swap it for a shared store when running several instances
+pub trait RateLimitStore: Send + Sync {
+    fn acquire(&self, key: &str, quota: &Quota) -> RateLimitDecision;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    // Once full again the bucket holds no state worth keeping
+    full_at: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    next_sweep_at: Option<Instant>,
+}
+
+// Token buckets kept in the memory of the process, shared by all workers
+#[derive(Default)]
+pub struct InMemoryRateLimitStore {
+    buckets: Mutex<Buckets>,
+}
+
+impl InMemoryRateLimitStore {
+    fn acquire_at(&self, key: &str, quota: &Quota, now: Instant) -> RateLimitDecision {
+        let capacity = f64::from(quota.requests);
+        let refill_rate = capacity / quota.period_seconds as f64;
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut key = key.to_string();
+        if buckets.by_key.len() >= MAX_BUCKETS && !buckets.by_key.contains_key(&key) {
+            // Only buckets that are full again are dropped, and not more often than the interval
+            // allows so that a flood of new callers does not walk all of them on every request
+            if buckets
+                .next_sweep_at
+                .is_none_or(|next_sweep_at| next_sweep_at <= now)
+            {
+                buckets.by_key.retain(|_, bucket| bucket.full_at > now);
+                buckets.next_sweep_at = Some(now + SWEEP_INTERVAL);
+            }
+
+            // Buckets in use are never taken away from their caller, which would hand it a full one
+            if buckets.by_key.len() >= MAX_BUCKETS {
+                key = format!("overflow:{}/{}", quota.requests, quota.period_seconds);
+            }
+        }
+
+        let bucket = buckets.by_key.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+            full_at: now,
+        });
+
+        // Refill for the time elapsed since the last request
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_rate).min(capacity);
+        bucket.updated_at = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        let retry_after_seconds = match allowed {
+            true => 0,
+            false => ((1.0 - bucket.tokens) / refill_rate).ceil() as u64,
+        };
+
+        let refill_seconds = (capacity - bucket.tokens) / refill_rate;
+        bucket.full_at = now + Duration::from_secs_f64(refill_seconds);
+
+        RateLimitDecision {
+            allowed,
+            limit: quota.requests,
+            remaining: bucket.tokens.floor() as u32,
+            reset_seconds: refill_seconds.ceil() as u64,
+            retry_after_seconds,
+        }
+    }
+}
+
+impl RateLimitStore for InMemoryRateLimitStore {
+    fn acquire(&self, key: &str, quota: &Quota) -> RateLimitDecision {
+        self.acquire_at(key, quota, Instant::now())
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self::with_store(config, Arc::new(InMemoryRateLimitStore::default()))
+    }
+
+    pub fn with_store(config: &RateLimitConfig, store: Arc<dyn RateLimitStore>) -> Self {
+        Self {
+            config: config.clone(),
+            store,
+        }
+    }
+}
+
+// Limit the requests of each caller per route group, enabled by registering a `RateLimiter` as app data
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(limiter) = req.app_data::<Data<RateLimiter>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    // The group is the first path segment below the scope the middleware wraps
+    let group = req
+        .match_info()
+        .unprocessed()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    let Some(quota) = limiter.config.groups.get(&group) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let acquire = |caller: &str| {
+        limiter
+            .store
+            .acquire(&format!("{}:{}", group, caller), quota)
+    };
+
+    let ip_caller = client_ip(req.request()).map(|ip| format!("ip:{}", ip));
+
+    let decision = if group == "auth" {
+        // Routes of the auth group are called before having credentials
+        ip_caller.map(|caller| acquire(&caller))
+    } else if let Some(caller) = verified_caller(&req) {
+        Some(acquire(&caller))
+    } else {
+        // Until its credentials are verified the caller is only known by its IP address, which also
+        // bounds the database lookups made for credentials that do not exist
+        let ip_decision = ip_caller.map(|caller| acquire(&caller));
+        if let Some(decision) = ip_decision.filter(|decision| !decision.allowed) {
+            return Ok(too_many_requests(req, &decision));
+        }
+
+        // The principal is kept for the route, which does not authenticate the request again
+        match authenticate_request(req.request()).await {
+            Ok(principal) => {
+                let decision = acquire(&principal_key(&principal));
+                req.extensions_mut().insert(principal);
+                Some(decision)
+            }
+            Err(_) => ip_decision,
+        }
+    };
+
+    let Some(decision) = decision else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    if !decision.allowed {
+        return Ok(too_many_requests(req, &decision));
+    }
+
+    let mut response = next.call(req).await?.map_into_boxed_body();
+    insert_rate_limit_headers(response.headers_mut(), &decision);
+
+    Ok(response)
+}
+
+fn too_many_requests(
+    req: ServiceRequest,
+    decision: &RateLimitDecision,
+) -> ServiceResponse<BoxBody> {
+    let error = ApiError::TooManyRequests(
+        "Rate limit exceeded".to_string(),
+        decision.retry_after_seconds as i64,
+    );
+    let mut response = req.into_response(error.error_response());
+    insert_rate_limit_headers(response.headers_mut(), decision);
+
+    response
+}
+
+// Identify the caller without touching the database, by the principal when its credentials are verified
+// in memory or by certificate for client certificates. `None` when the credentials still have to be looked up
+fn verified_caller(req: &ServiceRequest) -> Option<String> {
+    if let Some(principal) = authenticate_from_memory(req.request()) {
+        let caller = principal_key(&principal);
+        req.extensions_mut().insert(principal);
+
+        return Some(caller);
+    }
+
+    // The certificate was verified during the TLS handshake
+    if !req.headers().contains_key(AUTHORIZATION) {
+        if let Some(certificate) = req.conn_data::<ClientCertificate>() {
+            return Some(format!("cert:{}", certificate.fingerprint()));
+        }
+    }
+
+    None
+}
+
+// API keys have a bucket of their own, other credentials share the bucket of their user
+fn principal_key(principal: &Principal) -> String {
+    match &principal.api_key_uuid {
+        Some(api_key_uuid) => format!("key:{}", api_key_uuid),
+        None => format!("user:{}", principal.user_uuid),
+    }
+}
+
+fn insert_rate_limit_headers(headers: &mut HeaderMap, decision: &RateLimitDecision) {
+    let values = [
+        ("ratelimit-limit", decision.limit as u64),
+        ("ratelimit-remaining", decision.remaining as u64),
+        ("ratelimit-reset", decision.reset_seconds),
+    ];
+
+    for (name, value) in values {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_depletes_and_refills() {
+        let store = InMemoryRateLimitStore::default();
+        let quota = Quota {
+            requests: 2,
+            period_seconds: 10,
+        };
+        let start = Instant::now();
+
+        let first = store.acquire_at("ip:127.0.0.1", &quota, start);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+
+        let second = store.acquire_at("ip:127.0.0.1", &quota, start);
+        assert!(second.allowed);
+        assert_eq!(second.remaining, 0);
+        assert_eq!(second.reset_seconds, 10);
+
+        let third = store.acquire_at("ip:127.0.0.1", &quota, start);
+        assert!(!third.allowed);
+        assert_eq!(third.retry_after_seconds, 5);
+
+        // Other callers have their own bucket
+        assert!(store.acquire_at("ip:127.0.0.2", &quota, start).allowed);
+
+        // One request is refilled every 5 seconds
+        let later = start + Duration::from_secs(5);
+        assert!(store.acquire_at("ip:127.0.0.1", &quota, later).allowed);
+        assert!(!store.acquire_at("ip:127.0.0.1", &quota, later).allowed);
+    }
+
+    #[test]
+    fn test_buckets_are_evicted() {
+        let store = InMemoryRateLimitStore::default();
+        let quota = Quota {
+            requests: 1,
+            period_seconds: 10,
+        };
+        let start = Instant::now();
+
+        for i in 0..MAX_BUCKETS {
+            store.acquire_at(&format!("ip:{}", i), &quota, start);
+        }
+        let later = start + Duration::from_secs(1);
+        assert!(!store.acquire_at("ip:0", &quota, later).allowed);
+
+        // Buckets in use are kept, new callers share a bucket instead
+        assert!(store.acquire_at("ip:new", &quota, later).allowed);
+        assert!(!store.acquire_at("ip:other", &quota, later).allowed);
+        assert!(!store.acquire_at("ip:0", &quota, later).allowed);
+        assert_eq!(store.buckets.lock().unwrap().by_key.len(), MAX_BUCKETS + 1);
+
+        // Buckets that are full again are all dropped at once
+        let refilled = start + Duration::from_secs(20);
+        assert!(store.acquire_at("ip:other", &quota, refilled).allowed);
+        assert_eq!(store.buckets.lock().unwrap().by_key.len(), 1);
+    }
+}
@@ -0,0 +1,210 @@
+use super::api_key::Principal;
+use crate::{
+    config::Config,
+    db::DbPool,
+    errors::ApiError,
+    utils::auth::{RequestSignature, SigningKeySecret},
+};
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::header::AUTHORIZATION,
+    middleware::Next,
+    web::{Bytes, Data},
+    Error, HttpMessage, ResponseError,
+};
+use chrono::Utc;
+use futures_util::{future::ready, stream, Stream};
+use sqlx::query;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Upper bound of the nonces remembered at once, signed requests are refused past it
+const MAX_NONCES: usize = 100_000;
+
+// Nonces of the signed requests seen within the replay window, shared by all workers
+pub struct NonceCache {
+    window: Duration,
+    nonces: Mutex<HashMap<String, Instant>>,
+}
+
+impl NonceCache {
+    pub fn new(window_seconds: u64) -> Self {
+        Self {
+            window: Duration::from_secs(window_seconds),
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Remember the nonce of a key, fails if it was already used within the window
+    pub fn use_nonce(&self, key_id: &str, nonce: &str) -> Result<(), ApiError> {
+        self.use_nonce_at(key_id, nonce, Instant::now())
+    }
+
+    fn use_nonce_at(&self, key_id: &str, nonce: &str, now: Instant) -> Result<(), ApiError> {
+        let mut nonces = self.nonces.lock().unwrap_or_else(|e| e.into_inner());
+
+        let key = format!("{}:{}", key_id, nonce);
+        if let Some(seen_at) = nonces.get(&key) {
+            if now.duration_since(*seen_at) < self.window {
+                return Err(ApiError::Unauthorized("Nonce was already used".to_string()));
+            }
+        }
+
+        // Nonces older than the window can go, their timestamps are refused anyway
+        if nonces.len() >= MAX_NONCES {
+            nonces.retain(|_, seen_at| now.duration_since(*seen_at) < self.window);
+
+            if nonces.len() >= MAX_NONCES {
+                return Err(ApiError::TooManyRequests(
+                    "Too many signed requests, try again later".to_string(),
+                    1,
+                ));
+            }
+        }
+
+        nonces.insert(key, now);
+
+        Ok(())
+    }
+}
+
+// Authenticate requests signed with a signing key, the principal is left in the request extensions.
+// Requests with any other authorization scheme go through untouched
+pub async fn verify_request_signature(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(header) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| RequestSignature::is_signature_header(value))
+        .map(str::to_string)
+    else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    match authenticate(&mut req, &header).await {
+        Ok(principal) => {
+            req.extensions_mut().insert(principal);
+            Ok(next.call(req).await?.map_into_boxed_body())
+        }
+        Err(e) => Ok(req.into_response(e.error_response())),
+    }
+}
+
+async fn authenticate(req: &mut ServiceRequest, header: &str) -> Result<Principal, ApiError> {
+    let signature = RequestSignature::parse(header)?;
+
+    let (Some(pool), Some(config), Some(nonce_cache)) = (
+        req.app_data::<Data<DbPool>>().cloned(),
+        req.app_data::<Data<Config>>().cloned(),
+        req.app_data::<Data<NonceCache>>().cloned(),
+    ) else {
+        return Err(ApiError::ConfigError(
+            "Request signing state is not registered".to_string(),
+        ));
+    };
+
+    // Requests outside the replay window are refused, within it the nonce prevents replays
+    let window = config.auth.signature_replay_window_seconds as i64;
+    if (Utc::now().timestamp() - signature.timestamp).abs() > window {
+        return Err(ApiError::Unauthorized(
+            "Signature timestamp is outside the allowed window".to_string(),
+        ));
+    }
+
+    let signing_key = query!(
+        "
+        SELECT user_uuid, organization_uuid, secret, secret_version FROM signing_keys
+        WHERE uuid = ? AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        ",
+        &signature.key_id
+    )
+    .fetch_optional(pool.get_pool())
+    .await?
+    .ok_or_else(|| ApiError::Unauthorized("Invalid signature".to_string()))?;
+
+    let encryption = &config.auth.signing_key_encryption;
+    let stored_secret = SigningKeySecret {
+        version: signing_key.secret_version,
+        stored: signing_key.secret,
+    };
+    let secret = stored_secret
+        .decrypt(&signature.key_id, encryption)
+        .ok_or_else(|| ApiError::Unauthorized("Invalid signature".to_string()))?;
+
+    // The body is buffered to be hashed, then handed back to the handler
+    let body = req
+        .extract::<Bytes>()
+        .await
+        .map_err(|e| ApiError::InvalidInput(format!("Failed to read the body: {}", e)))?;
+    req.set_payload(bytes_payload(body.clone()));
+
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or_else(|| req.path());
+
+    if !signature.verify(&secret, req.method().as_str(), path_and_query, &body) {
+        return Err(ApiError::Unauthorized("Invalid signature".to_string()));
+    }
+
+    nonce_cache.use_nonce(&signature.key_id, &signature.nonce)?;
+
+    // Secrets stored in clear or with a retired server secret are encrypted with the current one
+    if stored_secret.is_outdated(encryption) {
+        let encrypted = SigningKeySecret::encrypt(&secret, &signature.key_id, encryption);
+        query!(
+            "
+            UPDATE signing_keys SET secret = ?, secret_version = ?
+            WHERE uuid = ? AND secret = ?
+            ",
+            encrypted.stored,
+            encrypted.version,
+            &signature.key_id,
+            stored_secret.stored
+        )
+        .execute(pool.get_pool())
+        .await?;
+    }
+
+    Ok(Principal {
+        user_uuid: signing_key.user_uuid,
+        api_key_uuid: None,
+        signing_key_uuid: Some(signature.key_id),
+        organization_uuid: signing_key.organization_uuid,
+    })
+}
+
+fn bytes_payload(body: Bytes) -> Payload {
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(stream::once(ready(Ok(body))));
+
+    Payload::from(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nonce_is_single_use_within_window() {
+        let cache = NonceCache::new(300);
+        let now = Instant::now();
+
+        assert!(cache.use_nonce_at("key", "nonce", now).is_ok());
+        assert!(cache.use_nonce_at("key", "nonce", now).is_err());
+        assert!(cache.use_nonce_at("other-key", "nonce", now).is_ok());
+        assert!(cache
+            .use_nonce_at("key", "nonce", now + Duration::from_secs(300))
+            .is_ok());
+    }
+}
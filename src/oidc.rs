@@ -0,0 +1,300 @@
+use crate::{config::OidcConfig, errors::ApiError};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use reqwest::Client;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+// An unreachable identity provider must not hold a worker for long
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long the discovery document and the provider keys are reused before being fetched again
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+
+// The subset of the discovery document needed for the authorization code flow
+#[derive(Deserialize, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// The claims of a validated ID token
+#[derive(Deserialize, Debug)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    nonce: Option<String>,
+}
+
+// A value fetched from the identity provider, shared until it is older than the cache TTL
+struct Cached<T> {
+    value: Arc<T>,
+    fetched_at: Instant,
+}
+
+impl<T> Cached<T> {
+    fn fresh(slot: &Mutex<Option<Self>>, now: Instant) -> Option<Arc<T>> {
+        let slot = slot.lock().unwrap_or_else(|e| e.into_inner());
+
+        slot.as_ref()
+            .filter(|cached| now.duration_since(cached.fetched_at) < CACHE_TTL)
+            .map(|cached| cached.value.clone())
+    }
+
+    fn store(slot: &Mutex<Option<Self>>, value: T) -> Arc<T> {
+        let value = Arc::new(value);
+        *slot.lock().unwrap_or_else(|e| e.into_inner()) = Some(Self {
+            value: value.clone(),
+            fetched_at: Instant::now(),
+        });
+
+        value
+    }
+}
+
+// Client of the configured identity provider, created once and shared by all workers
+pub struct OidcClient {
+    http: Client,
+    metadata: Mutex<Option<Cached<ProviderMetadata>>>,
+    jwks: Mutex<Option<Cached<JwkSet>>>,
+}
+
+impl OidcClient {
+    pub fn new() -> Result<Self, ApiError> {
+        let http = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            http,
+            metadata: Mutex::new(None),
+            jwks: Mutex::new(None),
+        })
+    }
+
+    // The discovery document of the configured issuer
+    async fn metadata(&self, config: &OidcConfig) -> Result<Arc<ProviderMetadata>, ApiError> {
+        if let Some(metadata) = Cached::fresh(&self.metadata, Instant::now()) {
+            return Ok(metadata);
+        }
+
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = self
+            .http
+            .get(discovery_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // The discovery document must describe the issuer we trust, not another one
+        if metadata.issuer.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+            return Err(ApiError::OidcError(
+                "Discovery document issuer does not match the configured issuer".to_string(),
+            ));
+        }
+
+        Ok(Cached::store(&self.metadata, metadata))
+    }
+
+    // The signing keys of the identity provider, fetched again when asked to after a key rotation
+    async fn jwks(&self, jwks_uri: &str, refresh: bool) -> Result<Arc<JwkSet>, ApiError> {
+        if !refresh {
+            if let Some(jwks) = Cached::fresh(&self.jwks, Instant::now()) {
+                return Ok(jwks);
+            }
+        }
+
+        let jwks: JwkSet = self
+            .http
+            .get(jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(Cached::store(&self.jwks, jwks))
+    }
+
+    // URL of the identity provider login page the user must be sent to
+    pub async fn authorization_url(
+        &self,
+        config: &OidcConfig,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<String, ApiError> {
+        let metadata = self.metadata(config).await?;
+        let mut url = Url::parse(&metadata.authorization_endpoint)
+            .map_err(|e| ApiError::OidcError(format!("Invalid authorization endpoint: {}", e)))?;
+
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &config.client_id)
+            .append_pair("redirect_uri", &config.redirect_uri)
+            .append_pair("scope", &config.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", &code_challenge(code_verifier))
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(url.to_string())
+    }
+
+    // Exchange the authorization code for an ID token and return its validated claims
+    pub async fn exchange_code(
+        &self,
+        config: &OidcConfig,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, ApiError> {
+        let metadata = self.metadata(config).await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &config.redirect_uri),
+            ("client_id", &config.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(client_secret) = &config.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+
+        let token_response: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        self.validate_id_token(config, &metadata, &token_response.id_token, nonce)
+            .await
+    }
+
+    // Check the signature against the provider keys, then the issuer, audience, expiry and nonce
+    async fn validate_id_token(
+        &self,
+        config: &OidcConfig,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, ApiError> {
+        let invalid = || ApiError::Unauthorized("Invalid ID token".to_string());
+
+        let header = decode_header(id_token).map_err(|_| invalid())?;
+
+        // Symmetric algorithms would let anyone knowing the client secret forge tokens
+        if !matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+                | Algorithm::ES256
+                | Algorithm::ES384
+                | Algorithm::EdDSA
+        ) {
+            return Err(invalid());
+        }
+
+        let find_key = |jwks: &JwkSet| {
+            match &header.kid {
+                Some(kid) => jwks.find(kid),
+                None => jwks.keys.first(),
+            }
+            .cloned()
+        };
+
+        // A key missing from the cached set may have been published since it was fetched
+        let jwk = match find_key(&*self.jwks(&metadata.jwks_uri, false).await?) {
+            Some(jwk) => jwk,
+            None => find_key(&*self.jwks(&metadata.jwks_uri, true).await?).ok_or_else(invalid)?,
+        };
+
+        if matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_)) {
+            return Err(invalid());
+        }
+
+        let decoding_key = DecodingKey::from_jwk(&jwk).map_err(|_| invalid())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims = decode::<IdTokenClaims>(id_token, &decoding_key, &validation)
+            .map_err(|_| invalid())?
+            .claims;
+
+        // The nonce binds the ID token to the login attempt that was started here
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(invalid());
+        }
+
+        Ok(claims)
+    }
+}
+
+// PKCE S256 code challenge of a code verifier, as defined in RFC 7636
+pub fn code_challenge(code_verifier: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(code_verifier.as_bytes());
+
+    URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_values_expire() {
+        let slot = Mutex::new(None);
+        let now = Instant::now();
+        assert!(Cached::<u8>::fresh(&slot, now).is_none());
+
+        Cached::store(&slot, 42);
+        assert_eq!(Cached::fresh(&slot, Instant::now()).as_deref(), Some(&42));
+        assert!(Cached::<u8>::fresh(&slot, Instant::now() + CACHE_TTL).is_none());
+    }
+
+    #[test]
+    fn code_challenge_matches_rfc_example() {
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+}
@@ -1,11 +1,59 @@
-use actix_web::web::ServiceConfig;
+use crate::errors::ApiError;
+use actix_web::web::{JsonConfig, PathConfig, QueryConfig, ServiceConfig};
 
+mod admin;
 mod authentification;
+mod mfa;
+mod oidc;
+mod organization;
 mod user;
 
 // Function to configure all routes
 pub fn v1_routes(cfg: &mut ServiceConfig) {
+    // Malformed bodies, paths and query strings are reported as problem details like any other error
+    cfg.app_data(
+        JsonConfig::default().error_handler(|e, _| ApiError::InvalidInput(e.to_string()).into()),
+    )
+    .app_data(
+        PathConfig::default().error_handler(|e, _| ApiError::InvalidInput(e.to_string()).into()),
+    )
+    .app_data(
+        QueryConfig::default().error_handler(|e, _| ApiError::InvalidInput(e.to_string()).into()),
+    );
+
     cfg.service(authentification::sign_up)
         .service(authentification::sign_in)
-        .service(user::get_user_metadata);
+        .service(authentification::sign_in_mfa)
+        .service(authentification::refresh_access_token)
+        .service(oidc::oidc_authorize)
+        .service(oidc::oidc_callback)
+        .service(authentification::forgot_password)
+        .service(authentification::reset_password)
+        .service(authentification::verify_email)
+        .service(authentification::resend_verification_email)
+        .service(user::get_user_metadata)
+        .service(user::change_password)
+        .service(user::delete_user)
+        .service(user::export_user)
+        .service(user::create_signing_key)
+        .service(user::delete_signing_key)
+        .service(user::restrict_api_key)
+        .service(mfa::enroll_totp)
+        .service(mfa::confirm_totp)
+        .service(mfa::disable_totp)
+        .service(mfa::regenerate_recovery_codes)
+        .service(organization::create_organization)
+        .service(organization::list_organizations)
+        .service(organization::accept_invitation)
+        .service(organization::list_members)
+        .service(organization::invite_member)
+        .service(organization::update_member)
+        .service(organization::remove_member)
+        .service(organization::create_organization_api_key)
+        .service(admin::unlock_sign_in)
+        .service(admin::get_audit_log)
+        .service(admin::list_certificate_identities)
+        .service(admin::create_certificate_identity)
+        .service(admin::delete_certificate_identity)
+        .service(admin::import_users);
 }
@@ -0,0 +1,399 @@
+use crate::{
+    audit::AuditEntry,
+    config::Config,
+    db::DbPool,
+    errors::{ApiError, Violation},
+    middleware::{
+        api_key::Admin,
+        lockout::{self, Scope},
+    },
+    responses::ApiResponse,
+    utils::{
+        auth::{Email, Password},
+        request::RequestContext,
+    },
+};
+use actix_web::{
+    delete, get, post,
+    web::{Data, Json, Path, Query},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{query, query_as, types::chrono::NaiveDateTime};
+use uuid::Uuid;
+
+// Upper bound of the audit log entries returned at once
+const MAX_AUDIT_LOG_LIMIT: u32 = 1000;
+
+// Upper bound of the users imported at once
+const MAX_IMPORTED_USERS: usize = 1000;
+
+// The kinds of names a client certificate can be mapped with, see `ClientCertificate::identities`
+const CERTIFICATE_IDENTITY_PREFIXES: [&str; 4] = ["uri:", "dns:", "email:", "cn:"];
+
+#[derive(Deserialize, Debug)]
+struct UnlockRequest {
+    email: Option<String>,
+    ip: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct UnlockResponse {
+    unlocked: bool,
+}
+
+#[post("/admin/lockouts/unlock")]
+async fn unlock_sign_in(
+    Admin(admin): Admin,
+    pool: Data<DbPool>,
+    context: RequestContext,
+    request_body: Json<UnlockRequest>,
+) -> Result<ApiResponse<UnlockResponse>, ApiError> {
+    // Accounts are locked under their canonical address
+    let (scope, identifier) = match (&request_body.email, &request_body.ip) {
+        (Some(email), None) => (
+            Scope::Account,
+            Email::new(email, false)
+                .map_err(|e| e.at("/email"))?
+                .canonical()
+                .to_string(),
+        ),
+        (None, Some(ip)) => (Scope::Ip, ip.clone()),
+        _ => {
+            return Err(ApiError::InvalidInput(
+                "Exactly one of email or ip is required".to_string(),
+            ))
+        }
+    };
+
+    let unlocked = lockout::unlock(&pool, scope, &identifier, &admin.user_uuid).await?;
+
+    if unlocked {
+        AuditEntry::new("admin.lockout_unlock")
+            .actor(&admin.user_uuid, admin.api_key_uuid.as_deref())
+            .target(format!("{}:{}", scope.as_str(), identifier))
+            .record(&pool, &context)
+            .await?;
+    }
+
+    let message = match unlocked {
+        true => "Sign-in unlocked successfully",
+        false => "Nothing to unlock",
+    };
+    Ok(ApiResponse::new(
+        Some(UnlockResponse { unlocked }),
+        Some(message.to_string()),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+struct AuditLogFilter {
+    actor_uuid: Option<String>,
+    action: Option<String>,
+    target: Option<String>,
+    since: Option<NaiveDateTime>,
+    until: Option<NaiveDateTime>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+struct AuditLogEntry {
+    uuid: String,
+    actor_uuid: Option<String>,
+    api_key_uuid: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    action: String,
+    target: Option<String>,
+    diff: Option<sqlx::types::Json<Value>>,
+    created_at: NaiveDateTime,
+}
+
+#[get("/admin/audit-log")]
+async fn get_audit_log(
+    _admin: Admin,
+    pool: Data<DbPool>,
+    filter: Query<AuditLogFilter>,
+) -> Result<ApiResponse<Vec<AuditLogEntry>>, ApiError> {
+    let limit = filter.limit.unwrap_or(100).min(MAX_AUDIT_LOG_LIMIT);
+    let offset = filter.offset.unwrap_or(0);
+
+    // Unset filters match every entry
+    let entries = query_as!(
+        AuditLogEntry,
+        r#"
+        SELECT uuid, actor_uuid, api_key_uuid, ip, user_agent, action, target,
+            diff AS `diff: sqlx::types::Json<Value>`, created_at
+        FROM audit_log
+        WHERE (? IS NULL OR actor_uuid = ?)
+            AND (? IS NULL OR action = ?)
+            AND (? IS NULL OR target = ?)
+            AND (? IS NULL OR created_at >= ?)
+            AND (? IS NULL OR created_at < ?)
+        ORDER BY created_at DESC, uuid
+        LIMIT ? OFFSET ?
+        "#,
+        filter.actor_uuid,
+        filter.actor_uuid,
+        filter.action,
+        filter.action,
+        filter.target,
+        filter.target,
+        filter.since,
+        filter.since,
+        filter.until,
+        filter.until,
+        limit,
+        offset
+    )
+    .fetch_all(pool.get_pool())
+    .await?;
+
+    let message = format!("{} audit log entries retrieved", entries.len());
+    Ok(ApiResponse::new(Some(entries), Some(message)))
+}
+
+#[derive(Deserialize, Debug)]
+struct CertificateIdentityRequest {
+    user_uuid: String,
+    identity: String,
+}
+
+#[derive(Serialize, Debug)]
+struct CertificateIdentity {
+    uuid: String,
+    user_uuid: String,
+    identity: String,
+    created_at: NaiveDateTime,
+}
+
+#[get("/admin/certificate-identities")]
+async fn list_certificate_identities(
+    _admin: Admin,
+    pool: Data<DbPool>,
+) -> Result<ApiResponse<Vec<CertificateIdentity>>, ApiError> {
+    let identities = query_as!(
+        CertificateIdentity,
+        "
+        SELECT uuid, user_uuid, identity, created_at FROM certificate_identities
+        ORDER BY created_at, uuid
+        "
+    )
+    .fetch_all(pool.get_pool())
+    .await?;
+
+    let message = format!("{} certificate identities retrieved", identities.len());
+    Ok(ApiResponse::new(Some(identities), Some(message)))
+}
+
+#[post("/admin/certificate-identities")]
+async fn create_certificate_identity(
+    Admin(admin): Admin,
+    pool: Data<DbPool>,
+    context: RequestContext,
+    request_body: Json<CertificateIdentityRequest>,
+) -> Result<ApiResponse<CertificateIdentity>, ApiError> {
+    let identity = request_body.identity.trim();
+
+    let has_known_prefix = CERTIFICATE_IDENTITY_PREFIXES
+        .iter()
+        .any(|prefix| identity.len() > prefix.len() && identity.starts_with(prefix));
+    if !has_known_prefix || identity.len() > 255 {
+        return Err(ApiError::InvalidInput(
+            "Identity must be up to 255 characters starting with uri:, dns:, email: or cn:"
+                .to_string(),
+        ));
+    }
+
+    let user = query!(
+        "SELECT uuid FROM users WHERE uuid = ? AND deleted_at IS NULL",
+        &request_body.user_uuid
+    )
+    .fetch_optional(pool.get_pool())
+    .await?;
+    if user.is_none() {
+        return Err(ApiError::InvalidInput("Unknown user".to_string()));
+    }
+
+    let existing = query!(
+        "SELECT uuid FROM certificate_identities WHERE identity = ?",
+        identity
+    )
+    .fetch_optional(pool.get_pool())
+    .await?;
+    if existing.is_some() {
+        return Err(ApiError::InvalidInput(
+            "Identity is already mapped to a user".to_string(),
+        ));
+    }
+
+    let uuid = Uuid::new_v4().to_string();
+    query!(
+        "INSERT INTO certificate_identities (uuid, user_uuid, identity) VALUES (?, ?, ?)",
+        uuid,
+        &request_body.user_uuid,
+        identity
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    let certificate_identity = query_as!(
+        CertificateIdentity,
+        "SELECT uuid, user_uuid, identity, created_at FROM certificate_identities WHERE uuid = ?",
+        uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    AuditEntry::new("admin.certificate_identity_create")
+        .actor(&admin.user_uuid, admin.api_key_uuid.as_deref())
+        .target(format!("user:{}", certificate_identity.user_uuid))
+        .diff(json!({ "identity": certificate_identity.identity }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        Some(certificate_identity),
+        Some("Certificate identity mapped successfully".to_string()),
+    ))
+}
+
+#[delete("/admin/certificate-identities/{uuid}")]
+async fn delete_certificate_identity(
+    Admin(admin): Admin,
+    pool: Data<DbPool>,
+    context: RequestContext,
+    path: Path<String>,
+) -> Result<ApiResponse<()>, ApiError> {
+    let uuid = path.into_inner();
+
+    let certificate_identity = query!(
+        "SELECT user_uuid, identity FROM certificate_identities WHERE uuid = ?",
+        uuid
+    )
+    .fetch_optional(pool.get_pool())
+    .await?
+    .ok_or_else(|| ApiError::NotFound("Unknown certificate identity".to_string()))?;
+
+    query!("DELETE FROM certificate_identities WHERE uuid = ?", uuid)
+        .execute(pool.get_pool())
+        .await?;
+
+    AuditEntry::new("admin.certificate_identity_delete")
+        .actor(&admin.user_uuid, admin.api_key_uuid.as_deref())
+        .target(format!("user:{}", certificate_identity.user_uuid))
+        .diff(json!({ "identity": certificate_identity.identity }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        None,
+        Some("Certificate identity deleted successfully".to_string()),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+struct ImportedUser {
+    email: String,
+    password_hash: String,
+    #[serde(default)]
+    verified: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImportRequest {
+    users: Vec<ImportedUser>,
+}
+
+#[derive(Serialize, Debug)]
+struct ImportResponse {
+    imported: usize,
+    skipped: Vec<String>,
+}
+
+#[post("/admin/users/import")]
+async fn import_users(
+    Admin(admin): Admin,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    context: RequestContext,
+    request_body: Json<ImportRequest>,
+) -> Result<ApiResponse<ImportResponse>, ApiError> {
+    let users = &request_body.users;
+    if users.is_empty() || users.len() > MAX_IMPORTED_USERS {
+        return Err(ApiError::InvalidInput(format!(
+            "Between 1 and {} users can be imported at once",
+            MAX_IMPORTED_USERS
+        )));
+    }
+
+    // The whole batch is refused if any entry is invalid, nothing is half imported
+    let mut emails = Vec::new();
+    let mut violations = Vec::new();
+    for (index, user) in users.iter().enumerate() {
+        match Email::new(&user.email, config.auth.lowercase_email_local_part)
+            .map_err(|e| e.at(&format!("/users/{}/email", index)))
+        {
+            Ok(email) => emails.push(email),
+            Err(ApiError::Validation(email_violations)) => violations.extend(email_violations),
+            Err(e) => return Err(e),
+        }
+
+        if Password::check_hash(&user.password_hash).is_err() {
+            violations.push(Violation {
+                pointer: Some(format!("/users/{}/password_hash", index)),
+                detail: "Unsupported password hash".to_string(),
+            });
+        }
+    }
+    if !violations.is_empty() {
+        return Err(ApiError::Validation(violations));
+    }
+
+    // Emails already taken, including by deleted users or earlier in the batch, are reported instead of overwritten
+    let mut skipped = Vec::new();
+    let mut transaction = pool.get_pool().begin().await?;
+    for (user, email) in users.iter().zip(&emails) {
+        let existing = query!(
+            "SELECT uuid FROM users WHERE email_canonical = ?",
+            email.canonical()
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if existing.is_some() {
+            skipped.push(email.as_str().to_string());
+            continue;
+        }
+
+        query!(
+            "
+            INSERT INTO users (uuid, email, email_canonical, password_hash, verified_at)
+            VALUES (?, ?, ?, ?, IF(?, CURRENT_TIMESTAMP, NULL))
+            ",
+            Uuid::new_v4().to_string(),
+            email.as_str(),
+            email.canonical(),
+            &user.password_hash,
+            user.verified
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+
+    let imported = users.len() - skipped.len();
+
+    AuditEntry::new("admin.user_import")
+        .actor(&admin.user_uuid, admin.api_key_uuid.as_deref())
+        .diff(json!({ "imported": imported, "skipped": skipped.len() }))
+        .record(&pool, &context)
+        .await?;
+
+    let message = format!("{} users imported", imported);
+    Ok(ApiResponse::new(
+        Some(ImportResponse { imported, skipped }),
+        Some(message),
+    ))
+}
@@ -1,15 +1,28 @@
+use super::mfa;
 use crate::{
+    audit::AuditEntry,
+    config::{ApiKeyHashConfig, Config, JwtConfig, PasswordHashConfig, TokenMode},
     db::DbPool,
     errors::ApiError,
+    mail::{Mail, Mailer},
+    middleware::{
+        api_key_cache::ApiKeyCache,
+        lockout::{self, Scope},
+    },
     responses::ApiResponse,
-    utils::auth::{ApiKey, Password},
+    utils::{
+        auth::{ApiKey, Email, JwtKeys, Password, Token},
+        request::{client_ip, RequestContext},
+    },
 };
 use actix_web::{
     post,
     web::{Data, Json},
-    Result,
+    HttpRequest, Result,
 };
+use chrono::{Duration, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sqlx::{query, query_as, types::chrono::NaiveDateTime};
 use uuid::Uuid;
 
@@ -29,38 +42,90 @@ struct UserMetadata {
 #[post("/auth/sign-up")]
 async fn sign_up(
     pool: Data<DbPool>,
+    config: Data<Config>,
+    mailer: Data<Mailer>,
+    context: RequestContext,
     request_body: Json<Credentials>,
-) -> Result<ApiResponse<UserMetadata>, ApiError> {
-    // Validate the password
-    let password = Password::new(&request_body.password)?;
+) -> Result<ApiResponse<()>, ApiError> {
+    // Validate the email address and the password, every broken rule of both is reported at once
+    let email = Email::new(&request_body.email, config.auth.lowercase_email_local_part)
+        .map_err(|e| e.at("/email"));
+    let password = Password::new(&request_body.password, &config.auth.password_policy)
+        .await
+        .map_err(|e| e.at("/password"));
 
-    // Register the user in the database
-    let user_metadata = register_user_in_db(&pool, &request_body.email, &password).await?;
+    let (email, password) = match (email, password) {
+        (Ok(email), Ok(password)) => (email, password),
+        (email, password) => {
+            let mut violations = Vec::new();
+            for error in [email.err(), password.err()].into_iter().flatten() {
+                match error {
+                    ApiError::Validation(field_violations) => violations.extend(field_violations),
+                    e => return Err(e),
+                }
+            }
+            return Err(ApiError::Validation(violations));
+        }
+    };
+
+    // Register the user in the database, the password is hashed either way so that the timing tells nothing
+    let user_metadata =
+        register_user_in_db(&pool, &config.auth.password_hash, &email, &password).await?;
+
+    // The response must not tell whether the email address was already registered, its owner is told instead
+    match user_metadata {
+        Some(user_metadata) => {
+            AuditEntry::new("user.sign_up")
+                .actor(&user_metadata.uuid, None)
+                .target(format!("user:{}", user_metadata.uuid))
+                .diff(json!({ "email": user_metadata.email }))
+                .record(&pool, &context)
+                .await?;
+
+            // Prove the user owns the email address
+            send_verification_token(&pool, &mailer, &user_metadata.uuid, &user_metadata.email)
+                .await?;
+        }
+        None => mailer.send_detached(Mail {
+            to: email.as_str().to_string(),
+            subject: "Sign-up attempt".to_string(),
+            body: "Someone tried to sign up with this email address, which already has an account. If it was you, sign in or reset your password instead. Otherwise, you can ignore this email.".to_string(),
+        }),
+    }
 
     Ok(ApiResponse::new(
-        Some(user_metadata),
-        Some("User registered successfully".to_string()),
+        None,
+        Some("Check your inbox to verify your email address".to_string()),
     ))
 }
 
+// Insert the user, nothing is returned when the email address is already taken
 async fn register_user_in_db(
     pool: &DbPool,
-    email: &str,
+    password_hash_config: &PasswordHashConfig,
+    email: &Email,
     password: &Password,
-) -> Result<UserMetadata, ApiError> {
+) -> Result<Option<UserMetadata>, ApiError> {
     let uuid = Uuid::new_v4().to_string();
 
-    let hashed_password = password.hash()?;
+    let hashed_password = password.hash(password_hash_config)?;
 
-    // First do the insert
-    query!(
-        "INSERT INTO users (uuid, email, password_hash) VALUES (?, ?, ?)",
+    // First do the insert, the email is unique regardless of its case, even among deleted users
+    let inserted = query!(
+        "INSERT INTO users (uuid, email, email_canonical, password_hash) VALUES (?, ?, ?, ?)",
         uuid,
-        email,
+        email.as_str(),
+        email.canonical(),
         hashed_password
     )
     .execute(pool.get_pool())
-    .await?;
+    .await;
+
+    match inserted {
+        Ok(_) => (),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
 
     // Then get the inserted row
     let user_metadata = query_as!(
@@ -71,103 +136,436 @@ async fn register_user_in_db(
     .fetch_one(pool.get_pool())
     .await?;
 
-    Ok(user_metadata)
+    Ok(Some(user_metadata))
 }
 
 #[derive(Serialize, Debug)]
 struct DbSignInResponse {
     uuid: String,
     password_hash: String,
+    verified_at: Option<NaiveDateTime>,
+    totp_enabled_at: Option<NaiveDateTime>,
 }
 
 #[derive(Debug)]
 struct VerifiedUser {
     uuid: String,
+    verified_at: Option<NaiveDateTime>,
+    totp_enabled_at: Option<NaiveDateTime>,
 }
 
 #[derive(Serialize, Debug)]
-struct ApiKeyResponse {
-    api_key: String,
-    created_at: NaiveDateTime,
-    expires_at: Option<NaiveDateTime>,
+pub(super) struct ApiKeyResponse {
+    pub(super) api_key: String,
+    pub(super) created_at: NaiveDateTime,
+    pub(super) expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Debug)]
+pub(super) struct TokenResponse {
+    access_token: String,
+    token_type: String,
+    expires_in: i64,
+    refresh_token: String,
+    refresh_token_expires_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Debug)]
+pub(super) struct MfaChallengeResponse {
+    mfa_token: String,
+}
+
+// The credentials depend on the token mode, and users with MFA enabled get a challenge to complete instead
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub(super) enum SignInResponse {
+    ApiKey(ApiKeyResponse),
+    Tokens(TokenResponse),
+    MfaChallenge(MfaChallengeResponse),
+}
+
+impl SignInResponse {
+    // Name of the issued credentials, used in the response message
+    pub(super) fn credentials_name(&self) -> &'static str {
+        match self {
+            Self::ApiKey(_) => "API key",
+            Self::Tokens(_) => "access token",
+            Self::MfaChallenge(_) => "MFA challenge",
+        }
+    }
 }
 
 #[post("/auth/sign-in")]
 async fn sign_in(
+    req: HttpRequest,
     pool: Data<DbPool>,
+    config: Data<Config>,
+    jwt_keys: Option<Data<JwtKeys>>,
+    context: RequestContext,
     request_body: Json<Credentials>,
-) -> Result<ApiResponse<ApiKeyResponse>, ApiError> {
-    // Verify user credentials
-    let password = Password::new(&request_body.password)?;
-    let verified_user = verify_user_credentials(&pool, &request_body.email, &password).await?;
+) -> Result<ApiResponse<SignInResponse>, ApiError> {
+    let email = Email::new(&request_body.email, config.auth.lowercase_email_local_part)
+        .map_err(|e| e.at("/email"))?;
+    let password = Password::existing(&request_body.password);
+
+    // Locked out accounts and client IPs are rejected before the password is even checked,
+    // accounts are locked under their canonical address so that changing its case does not help
+    let client_ip = client_ip(&req);
+    lockout::check_lockout(&pool, Scope::Account, email.canonical()).await?;
+    if let Some(client_ip) = &client_ip {
+        lockout::check_lockout(&pool, Scope::Ip, client_ip).await?;
+    }
+
+    // Verify user credentials, counting failures towards a lockout
+    let verified_user = match verify_user_credentials(
+        &pool,
+        &config.auth.password_hash,
+        &email,
+        request_body.email.trim(),
+        &password,
+    )
+    .await
+    {
+        Ok(verified_user) => verified_user,
+        Err(e @ ApiError::Unauthorized(_)) => {
+            let lockout_config = &config.auth.lockout;
+            lockout::record_failure(
+                &pool,
+                &context,
+                lockout_config,
+                Scope::Account,
+                email.canonical(),
+            )
+            .await?;
+            if let Some(client_ip) = &client_ip {
+                lockout::record_failure(&pool, &context, lockout_config, Scope::Ip, client_ip)
+                    .await?;
+            }
 
-    // Generate and store API key
-    let api_key = ApiKey::generate();
-    let api_key_metadata = store_api_key(&pool, &verified_user.uuid, &api_key).await?;
+            AuditEntry::new("auth.sign_in_failure")
+                .target(format!("email:{}", email.canonical()))
+                .diff(json!({ "method": "password" }))
+                .record(&pool, &context)
+                .await?;
+
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Unverified accounts may be blocked until the email address is confirmed
+    if config.auth.require_email_verification && verified_user.verified_at.is_none() {
+        return Err(ApiError::Unauthorized(
+            "Email address is not verified".to_string(),
+        ));
+    }
+
+    // Users of the identity provider may be required to sign in through it, where their account is managed
+    if config
+        .oidc
+        .as_ref()
+        .is_some_and(|oidc_config| oidc_config.disable_password_sign_in)
+    {
+        let identity = query!(
+            "SELECT uuid FROM user_identities WHERE user_uuid = ? LIMIT 1",
+            &verified_user.uuid
+        )
+        .fetch_optional(pool.get_pool())
+        .await?;
+
+        if identity.is_some() {
+            return Err(ApiError::Forbidden(
+                "Password sign-in is disabled for this account, sign in with the identity provider"
+                    .to_string(),
+            ));
+        }
+    }
+
+    // With MFA enabled the password is only the first step, the failures of the account are
+    // only forgotten once the second factor is verified
+    if verified_user.totp_enabled_at.is_some() {
+        let challenge = start_mfa_challenge(&pool, &verified_user.uuid).await?;
+
+        return Ok(ApiResponse::new(
+            Some(challenge),
+            Some("Password is correct, a TOTP or recovery code is required".to_string()),
+        ));
+    }
+
+    lockout::reset_failures(&pool, Scope::Account, email.canonical()).await?;
+
+    // Generate and store the credentials
+    let credentials = issue_credentials(
+        &pool,
+        &config,
+        jwt_keys.as_ref().map(Data::get_ref),
+        &context,
+        &verified_user.uuid,
+    )
+    .await?;
+
+    AuditEntry::new("auth.sign_in")
+        .actor(&verified_user.uuid, None)
+        .diff(json!({ "method": "password" }))
+        .record(&pool, &context)
+        .await?;
 
     // Return success response
+    let message = format!(
+        "Password is correct, {} generated successfully",
+        credentials.credentials_name()
+    );
+    Ok(ApiResponse::new(Some(credentials), Some(message)))
+}
+
+// Issue the credentials of the configured token mode to an authenticated user
+pub(super) async fn issue_credentials(
+    pool: &DbPool,
+    config: &Config,
+    jwt_keys: Option<&JwtKeys>,
+    context: &RequestContext,
+    user_uuid: &str,
+) -> Result<SignInResponse, ApiError> {
+    match &config.auth.token_mode {
+        TokenMode::ApiKey => {
+            let api_key = ApiKey::generate(&config.auth.api_key_format);
+            let api_key_metadata = store_api_key(
+                pool,
+                &config.auth.api_key_hash,
+                context,
+                user_uuid,
+                None,
+                &api_key,
+            )
+            .await?;
+
+            Ok(SignInResponse::ApiKey(ApiKeyResponse {
+                api_key: api_key.into_string(),
+                created_at: api_key_metadata.created_at,
+                expires_at: api_key_metadata.expires_at,
+            }))
+        }
+        TokenMode::Jwt(jwt_config) => {
+            // Every sign-in starts a new refresh token family
+            let family_uuid = Uuid::new_v4().to_string();
+            let jwt_keys = JwtKeys::registered(jwt_keys)?;
+            let tokens = issue_tokens(pool, jwt_keys, jwt_config, user_uuid, &family_uuid).await?;
+
+            Ok(SignInResponse::Tokens(tokens))
+        }
+    }
+}
+
+// Sign an access token and store a new refresh token in the given family
+async fn issue_tokens(
+    pool: &DbPool,
+    jwt_keys: &JwtKeys,
+    jwt_config: &JwtConfig,
+    user_uuid: &str,
+    family_uuid: &str,
+) -> Result<TokenResponse, ApiError> {
+    let access_token = jwt_keys.issue(user_uuid)?;
+
+    let uuid = Uuid::new_v4().to_string();
+    let refresh_token = Token::generate();
+    let token_hash = refresh_token.hash();
+    let expires_at = (Utc::now() + Duration::seconds(jwt_config.refresh_token_ttl)).naive_utc();
+
+    query!(
+        "
+        INSERT INTO refresh_tokens (uuid, user_uuid, family_uuid, token_hash, expires_at)
+        VALUES (?, ?, ?, ?, ?)
+        ",
+        uuid,
+        user_uuid,
+        family_uuid,
+        token_hash,
+        expires_at,
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    Ok(TokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: jwt_keys.access_token_ttl(),
+        refresh_token: refresh_token.as_str().to_string(),
+        refresh_token_expires_at: expires_at,
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct RefreshTokenRequest {
+    refresh_token: String,
+}
+
+#[post("/auth/token/refresh")]
+async fn refresh_access_token(
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    jwt_keys: Option<Data<JwtKeys>>,
+    context: RequestContext,
+    request_body: Json<RefreshTokenRequest>,
+) -> Result<ApiResponse<TokenResponse>, ApiError> {
+    let TokenMode::Jwt(jwt_config) = &config.auth.token_mode else {
+        return Err(ApiError::InvalidInput(
+            "Refresh tokens are only available in JWT token mode".to_string(),
+        ));
+    };
+
+    let refresh_token = Token::new(&request_body.refresh_token)?;
+    let token_hash = refresh_token.hash();
+
+    let stored_token = query!(
+        "
+        SELECT uuid, user_uuid, family_uuid, expires_at, used_at, revoked_at
+        FROM refresh_tokens WHERE token_hash = ?
+        ",
+        token_hash
+    )
+    .fetch_optional(pool.get_pool())
+    .await?
+    .ok_or_else(|| ApiError::Unauthorized("Invalid refresh token".to_string()))?;
+
+    if stored_token.revoked_at.is_some() || stored_token.expires_at < Utc::now().naive_utc() {
+        return Err(ApiError::Unauthorized(
+            "Invalid or expired refresh token".to_string(),
+        ));
+    }
+
+    // Rotation happens in a single statement so a token can only be exchanged once,
+    // a second exchange means the token leaked and the whole family is revoked
+    let result = query!(
+        "UPDATE refresh_tokens SET used_at = CURRENT_TIMESTAMP WHERE uuid = ? AND used_at IS NULL",
+        &stored_token.uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    if stored_token.used_at.is_some() || result.rows_affected() == 0 {
+        query!(
+            "
+            UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP
+            WHERE family_uuid = ? AND revoked_at IS NULL
+            ",
+            &stored_token.family_uuid
+        )
+        .execute(pool.get_pool())
+        .await?;
+
+        AuditEntry::new("refresh_token.revoke")
+            .target(format!("refresh_token_family:{}", stored_token.family_uuid))
+            .diff(json!({ "reason": "reuse", "user_uuid": stored_token.user_uuid }))
+            .record(&pool, &context)
+            .await?;
+
+        return Err(ApiError::Unauthorized(
+            "Refresh token reuse detected, all related tokens have been revoked".to_string(),
+        ));
+    }
+
+    let tokens = issue_tokens(
+        &pool,
+        JwtKeys::registered(jwt_keys.as_ref().map(Data::get_ref))?,
+        jwt_config,
+        &stored_token.user_uuid,
+        &stored_token.family_uuid,
+    )
+    .await?;
+
     Ok(ApiResponse::new(
-        Some(ApiKeyResponse {
-            api_key: api_key.into_string(),
-            created_at: api_key_metadata.created_at,
-            expires_at: api_key_metadata.expires_at,
-        }),
-        Some("Password is correct, API key generated successfully".to_string()),
+        Some(tokens),
+        Some("Refresh token is valid, access token generated successfully".to_string()),
     ))
 }
 
 // This function handles the database query and password verification
 async fn verify_user_credentials(
     pool: &DbPool,
-    email: &str,
+    password_hash_config: &PasswordHashConfig,
+    email: &Email,
+    typed_email: &str,
     password: &Password,
 ) -> Result<VerifiedUser, ApiError> {
-    // Query the database for user credentials
+    // Query the database for user credentials, accounts sharing their canonical address with an older one
+    // are only found by the exact address they registered with until the conflict is resolved
     let db_sign_in_response = query_as!(
         DbSignInResponse,
         "
-        SELECT uuid, password_hash 
-        FROM users WHERE email = ?
+        SELECT uuid, password_hash, verified_at, totp_enabled_at
+        FROM users
+        WHERE (email_canonical = ? OR (email_canonical IS NULL AND email = BINARY ?)) AND deleted_at IS NULL
+        ORDER BY email_canonical IS NULL DESC LIMIT 1
         ",
-        email
+        email.canonical(),
+        typed_email
     )
-    .fetch_one(pool.get_pool())
+    .fetch_optional(pool.get_pool())
     .await?;
 
+    // Unknown emails cost as much as wrong passwords and fail the same way
+    let Some(db_sign_in_response) = db_sign_in_response else {
+        password.verify_nothing(password_hash_config)?;
+        return Err(ApiError::Unauthorized("Invalid credentials".to_string()));
+    };
+
     // Verify the password - if verification fails, this will return early with an error
-    match password.verify(&db_sign_in_response.password_hash)? {
+    match password.verify(&db_sign_in_response.password_hash, password_hash_config)? {
         true => (),
         false => return Err(ApiError::Unauthorized("Invalid credentials".to_string())),
     }
 
+    // Hashes made with outdated parameters are upgraded while the password is at hand
+    if Password::needs_rehash(&db_sign_in_response.password_hash, password_hash_config)? {
+        query!(
+            "UPDATE users SET password_hash = ? WHERE uuid = ? AND password_hash = ?",
+            password.hash(password_hash_config)?,
+            &db_sign_in_response.uuid,
+            &db_sign_in_response.password_hash
+        )
+        .execute(pool.get_pool())
+        .await?;
+    }
+
     // If we get here, password verification succeeded
     Ok(VerifiedUser {
         uuid: db_sign_in_response.uuid,
+        verified_at: db_sign_in_response.verified_at,
+        totp_enabled_at: db_sign_in_response.totp_enabled_at,
     })
 }
 
 #[derive(Serialize, Debug)]
-struct ApiKeyMetadata {
-    created_at: NaiveDateTime,
-    expires_at: Option<NaiveDateTime>,
+pub(super) struct ApiKeyMetadata {
+    pub(super) created_at: NaiveDateTime,
+    pub(super) expires_at: Option<NaiveDateTime>,
 }
 
-// Store the API key in the database
-async fn store_api_key(
+// Store the API key in the database, keys with an organization act on behalf of it
+pub(super) async fn store_api_key(
     pool: &DbPool,
+    api_key_hash_config: &ApiKeyHashConfig,
+    context: &RequestContext,
     user_uuid: &str,
+    organization_uuid: Option<&str>,
     api_key: &ApiKey,
 ) -> Result<ApiKeyMetadata, ApiError> {
     let uuid = Uuid::new_v4().to_string();
 
-    let api_key_hash = api_key.hash();
+    let secret = api_key_hash_config.current();
+    let api_key_hash = api_key.hash(secret);
 
     // Store the API key in the database
     query!(
-        "INSERT INTO api_keys (uuid, user_uuid, api_key_hash) VALUES (?, ?, ?)",
+        "
+        INSERT INTO api_keys (uuid, user_uuid, organization_uuid, api_key_hash, api_key_hash_version)
+        VALUES (?, ?, ?, ?, ?)
+        ",
         uuid,
         user_uuid,
+        organization_uuid,
         api_key_hash,
+        secret.version,
     )
     .execute(pool.get_pool())
     .await?;
@@ -181,5 +579,447 @@ async fn store_api_key(
     .fetch_one(pool.get_pool())
     .await?;
 
+    AuditEntry::new("api_key.create")
+        .actor(user_uuid, None)
+        .target(format!("api_key:{}", uuid))
+        .diff(json!({
+            "organization_uuid": organization_uuid,
+            "expires_at": api_key_metadata.expires_at,
+        }))
+        .record(pool, context)
+        .await?;
+
     Ok(api_key_metadata)
 }
+
+#[derive(Deserialize, Debug)]
+struct ForgotPassword {
+    email: String,
+}
+
+#[post("/auth/password/forgot")]
+async fn forgot_password(
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    mailer: Data<Mailer>,
+    request_body: Json<ForgotPassword>,
+) -> Result<ApiResponse<()>, ApiError> {
+    let email = Email::new(&request_body.email, config.auth.lowercase_email_local_part)
+        .map_err(|e| e.at("/email"))?;
+    let typed_email = request_body.email.trim();
+
+    let user = query!(
+        "
+        SELECT uuid, email FROM users
+        WHERE (email_canonical = ? OR (email_canonical IS NULL AND email = BINARY ?)) AND deleted_at IS NULL
+        ORDER BY email_canonical IS NULL DESC LIMIT 1
+        ",
+        email.canonical(),
+        typed_email
+    )
+    .fetch_optional(pool.get_pool())
+    .await?;
+
+    // Only existing users get a token, but the response must not tell the difference
+    if let Some(user) = user {
+        let token = Token::generate();
+        store_password_reset_token(&pool, &user.uuid, &token).await?;
+
+        // The mail goes to the registered address, not to however the user typed it
+        mailer.send_detached(Mail {
+            to: user.email,
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Use the following token to reset your password, it is valid for one hour and can only be used once:\n\n{}",
+                token.as_str()
+            ),
+        });
+    }
+
+    Ok(ApiResponse::new(
+        None,
+        Some(
+            "If an account exists for this email, a password reset token has been sent".to_string(),
+        ),
+    ))
+}
+
+// Store the hash of a password reset token, the expiry is set by the database
+async fn store_password_reset_token(
+    pool: &DbPool,
+    user_uuid: &str,
+    token: &Token,
+) -> Result<(), ApiError> {
+    let uuid = Uuid::new_v4().to_string();
+
+    let token_hash = token.hash();
+
+    query!(
+        "INSERT INTO password_reset_tokens (uuid, user_uuid, token_hash) VALUES (?, ?, ?)",
+        uuid,
+        user_uuid,
+        token_hash,
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct ResetPassword {
+    token: String,
+    new_password: String,
+}
+
+#[post("/auth/password/reset")]
+async fn reset_password(
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    api_key_cache: Data<ApiKeyCache>,
+    context: RequestContext,
+    request_body: Json<ResetPassword>,
+) -> Result<ApiResponse<()>, ApiError> {
+    // Validate the inputs before consuming the token
+    let token = Token::new(&request_body.token)?;
+    let password = Password::new(&request_body.new_password, &config.auth.password_policy)
+        .await
+        .map_err(|e| e.at("/new_password"))?;
+
+    let user_uuid = consume_password_reset_token(&pool, &token).await?;
+
+    // Store the new password
+    let password_hash = password.hash(&config.auth.password_hash)?;
+    query!(
+        "UPDATE users SET password_hash = ? WHERE uuid = ?",
+        password_hash,
+        &user_uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    AuditEntry::new("user.password_reset")
+        .actor(&user_uuid, None)
+        .target(format!("user:{}", user_uuid))
+        .diff(json!({ "password_hash": "changed" }))
+        .record(&pool, &context)
+        .await?;
+
+    // Keys issued before the reset may belong to whoever knew the old password
+    let revoked_api_keys = query!("DELETE FROM api_keys WHERE user_uuid = ?", &user_uuid)
+        .execute(pool.get_pool())
+        .await?
+        .rows_affected();
+    api_key_cache.invalidate_user(&user_uuid);
+    query!("DELETE FROM signing_keys WHERE user_uuid = ?", &user_uuid)
+        .execute(pool.get_pool())
+        .await?;
+    query!(
+        "UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP WHERE user_uuid = ? AND revoked_at IS NULL",
+        &user_uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    AuditEntry::new("api_key.revoke")
+        .actor(&user_uuid, None)
+        .target(format!("user:{}", user_uuid))
+        .diff(json!({ "reason": "password_reset", "revoked_api_keys": revoked_api_keys }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        None,
+        Some("Password reset successfully".to_string()),
+    ))
+}
+
+// Mark the token as used and return the uuid of its user, fails if the token is unknown, used or expired
+async fn consume_password_reset_token(pool: &DbPool, token: &Token) -> Result<String, ApiError> {
+    let token_hash = token.hash();
+
+    // Marking the token as used in a single statement guarantees it can only be consumed once
+    let result = query!(
+        "
+        UPDATE password_reset_tokens SET used_at = CURRENT_TIMESTAMP
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        ",
+        token_hash
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Unauthorized(
+            "Invalid or expired token".to_string(),
+        ));
+    }
+
+    let password_reset_token = query!(
+        "SELECT user_uuid FROM password_reset_tokens WHERE token_hash = ?",
+        token_hash
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    Ok(password_reset_token.user_uuid)
+}
+
+// Generate a verification token for the user and mail it to the given address
+async fn send_verification_token(
+    pool: &DbPool,
+    mailer: &Mailer,
+    user_uuid: &str,
+    email: &str,
+) -> Result<(), ApiError> {
+    let uuid = Uuid::new_v4().to_string();
+
+    let token = Token::generate();
+    let token_hash = token.hash();
+
+    query!(
+        "INSERT INTO email_verification_tokens (uuid, user_uuid, token_hash) VALUES (?, ?, ?)",
+        uuid,
+        user_uuid,
+        token_hash,
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    mailer.send_detached(Mail {
+        to: email.to_string(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Use the following token to verify your email address, it is valid for 24 hours and can only be used once:\n\n{}",
+            token.as_str()
+        ),
+    });
+
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct VerifyEmail {
+    token: String,
+}
+
+#[post("/auth/email/verify")]
+async fn verify_email(
+    pool: Data<DbPool>,
+    context: RequestContext,
+    request_body: Json<VerifyEmail>,
+) -> Result<ApiResponse<()>, ApiError> {
+    let token = Token::new(&request_body.token)?;
+    let token_hash = token.hash();
+
+    // Marking the token as used in a single statement guarantees it can only be consumed once
+    let result = query!(
+        "
+        UPDATE email_verification_tokens SET used_at = CURRENT_TIMESTAMP
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        ",
+        token_hash
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Unauthorized(
+            "Invalid or expired token".to_string(),
+        ));
+    }
+
+    let verification_token = query!(
+        "SELECT user_uuid FROM email_verification_tokens WHERE token_hash = ?",
+        token_hash
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+    let user_uuid = verification_token.user_uuid;
+
+    let result = query!(
+        "UPDATE users SET verified_at = CURRENT_TIMESTAMP WHERE uuid = ? AND verified_at IS NULL",
+        &user_uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    if result.rows_affected() == 1 {
+        AuditEntry::new("user.email_verify")
+            .actor(&user_uuid, None)
+            .target(format!("user:{}", user_uuid))
+            .diff(json!({ "verified_at": "set" }))
+            .record(&pool, &context)
+            .await?;
+    }
+
+    Ok(ApiResponse::new(
+        None,
+        Some("Email address verified successfully".to_string()),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+struct ResendVerification {
+    email: String,
+}
+
+#[post("/auth/email/resend")]
+async fn resend_verification_email(
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    mailer: Data<Mailer>,
+    request_body: Json<ResendVerification>,
+) -> Result<ApiResponse<()>, ApiError> {
+    let email = Email::new(&request_body.email, config.auth.lowercase_email_local_part)
+        .map_err(|e| e.at("/email"))?;
+    let typed_email = request_body.email.trim();
+
+    let user = query!(
+        "
+        SELECT uuid, email FROM users
+        WHERE (email_canonical = ? OR (email_canonical IS NULL AND email = BINARY ?))
+            AND verified_at IS NULL AND deleted_at IS NULL
+        ORDER BY email_canonical IS NULL DESC LIMIT 1
+        ",
+        email.canonical(),
+        typed_email
+    )
+    .fetch_optional(pool.get_pool())
+    .await?;
+
+    // Only unverified users get a token, but the response must not tell the difference
+    if let Some(user) = user {
+        send_verification_token(&pool, &mailer, &user.uuid, &user.email).await?;
+    }
+
+    Ok(ApiResponse::new(
+        None,
+        Some(
+            "If an unverified account exists for this email, a verification token has been sent"
+                .to_string(),
+        ),
+    ))
+}
+
+// Store the hash of a token proving the first step of the sign-in succeeded, the code is asked with it
+pub(super) async fn start_mfa_challenge(
+    pool: &DbPool,
+    user_uuid: &str,
+) -> Result<SignInResponse, ApiError> {
+    let uuid = Uuid::new_v4().to_string();
+
+    let mfa_token = Token::generate();
+    let token_hash = mfa_token.hash();
+
+    query!(
+        "INSERT INTO mfa_challenges (uuid, user_uuid, token_hash) VALUES (?, ?, ?)",
+        uuid,
+        user_uuid,
+        token_hash,
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    Ok(SignInResponse::MfaChallenge(MfaChallengeResponse {
+        mfa_token: mfa_token.as_str().to_string(),
+    }))
+}
+
+#[derive(Deserialize, Debug)]
+struct MfaCode {
+    mfa_token: String,
+    code: String,
+}
+
+#[post("/auth/sign-in/mfa")]
+async fn sign_in_mfa(
+    req: HttpRequest,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    jwt_keys: Option<Data<JwtKeys>>,
+    context: RequestContext,
+    request_body: Json<MfaCode>,
+) -> Result<ApiResponse<SignInResponse>, ApiError> {
+    let mfa_token = Token::new(&request_body.mfa_token)?;
+    let token_hash = mfa_token.hash();
+
+    // The challenge is consumed by any attempt, a wrong code requires signing in with the password again
+    let result = query!(
+        "
+        UPDATE mfa_challenges SET used_at = CURRENT_TIMESTAMP
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        ",
+        token_hash
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Unauthorized(
+            "Invalid or expired MFA token".to_string(),
+        ));
+    }
+
+    let user = query!(
+        "
+        SELECT users.uuid, users.email
+        FROM mfa_challenges JOIN users ON users.uuid = mfa_challenges.user_uuid
+        WHERE mfa_challenges.token_hash = ?
+        ",
+        token_hash
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    // Codes are guessed against the same lockout as passwords, the account being locked under the
+    // canonical address it signs in with, or its stored address when that one is not valid
+    let account = Email::new(&user.email, config.auth.lowercase_email_local_part).map_or_else(
+        |_| user.email.clone(),
+        |email| email.canonical().to_string(),
+    );
+    let client_ip = client_ip(&req);
+    lockout::check_lockout(&pool, Scope::Account, &account).await?;
+    if let Some(client_ip) = &client_ip {
+        lockout::check_lockout(&pool, Scope::Ip, client_ip).await?;
+    }
+
+    if !mfa::verify_mfa_code(&pool, &user.uuid, &request_body.code).await? {
+        let lockout_config = &config.auth.lockout;
+        lockout::record_failure(&pool, &context, lockout_config, Scope::Account, &account).await?;
+        if let Some(client_ip) = &client_ip {
+            lockout::record_failure(&pool, &context, lockout_config, Scope::Ip, client_ip).await?;
+        }
+
+        AuditEntry::new("auth.sign_in_failure")
+            .target(format!("user:{}", user.uuid))
+            .diff(json!({ "method": "mfa" }))
+            .record(&pool, &context)
+            .await?;
+
+        return Err(ApiError::Unauthorized("Invalid MFA code".to_string()));
+    }
+    lockout::reset_failures(&pool, Scope::Account, &account).await?;
+
+    // Generate and store the credentials
+    let credentials = issue_credentials(
+        &pool,
+        &config,
+        jwt_keys.as_ref().map(Data::get_ref),
+        &context,
+        &user.uuid,
+    )
+    .await?;
+
+    AuditEntry::new("auth.sign_in")
+        .actor(&user.uuid, None)
+        .diff(json!({ "method": "mfa" }))
+        .record(&pool, &context)
+        .await?;
+
+    let message = format!(
+        "MFA code is correct, {} generated successfully",
+        credentials.credentials_name()
+    );
+    Ok(ApiResponse::new(Some(credentials), Some(message)))
+}
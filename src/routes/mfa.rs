@@ -0,0 +1,408 @@
+use crate::{
+    audit::AuditEntry,
+    config::{Config, PasswordHashConfig},
+    db::DbPool,
+    errors::ApiError,
+    middleware::api_key::Principal,
+    responses::ApiResponse,
+    utils::{
+        auth::{Password, RecoveryCode, Totp},
+        request::RequestContext,
+    },
+};
+use actix_web::{
+    delete, post,
+    web::{Data, Json},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::query;
+use uuid::Uuid;
+
+// Number of recovery codes issued when TOTP is enabled
+const RECOVERY_CODES_COUNT: usize = 10;
+
+#[derive(Deserialize, Debug)]
+struct PasswordConfirmation {
+    password: String,
+}
+
+#[derive(Serialize, Debug)]
+struct TotpEnrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[post("/user/mfa/totp")]
+async fn enroll_totp(
+    principal: Principal,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    context: RequestContext,
+    request_body: Json<PasswordConfirmation>,
+) -> Result<ApiResponse<TotpEnrollment>, ApiError> {
+    principal.require_personal()?;
+
+    let uuid = &principal.user_uuid;
+
+    // A leaked API key alone must not be enough to lock the owner out behind a second factor
+    confirm_password(
+        &pool,
+        &config.auth.password_hash,
+        uuid,
+        &request_body.password,
+    )
+    .await?;
+
+    let user = query!(
+        "SELECT email, totp_enabled_at FROM users WHERE uuid = ?",
+        &uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    if user.totp_enabled_at.is_some() {
+        return Err(ApiError::InvalidInput(
+            "TOTP is already enabled".to_string(),
+        ));
+    }
+
+    // The secret stays pending until a first code is confirmed, a new enrollment replaces it
+    let totp = Totp::generate();
+    let secret = totp.to_base32();
+    query!(
+        "UPDATE users SET totp_secret = ?, totp_last_step = NULL WHERE uuid = ?",
+        &secret,
+        uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    AuditEntry::new("user.mfa_enroll")
+        .actor(uuid, principal.api_key_uuid.as_deref())
+        .target(format!("user:{}", uuid))
+        .diff(json!({ "totp_secret": "changed" }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        Some(TotpEnrollment {
+            otpauth_uri: totp.uri(&user.email),
+            secret,
+        }),
+        Some("TOTP secret generated, confirm it with a code to enable it".to_string()),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+struct TotpConfirmation {
+    password: String,
+    code: String,
+}
+
+#[derive(Serialize, Debug)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[post("/user/mfa/totp/confirm")]
+async fn confirm_totp(
+    principal: Principal,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    context: RequestContext,
+    request_body: Json<TotpConfirmation>,
+) -> Result<ApiResponse<RecoveryCodes>, ApiError> {
+    principal.require_personal()?;
+
+    let uuid = &principal.user_uuid;
+
+    confirm_password(
+        &pool,
+        &config.auth.password_hash,
+        uuid,
+        &request_body.password,
+    )
+    .await?;
+
+    let user = query!(
+        "SELECT totp_secret, totp_last_step, totp_enabled_at FROM users WHERE uuid = ?",
+        &uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    let secret = match (user.totp_secret, user.totp_enabled_at) {
+        (_, Some(_)) => {
+            return Err(ApiError::InvalidInput(
+                "TOTP is already enabled".to_string(),
+            ))
+        }
+        (None, None) => {
+            return Err(ApiError::InvalidInput(
+                "No pending TOTP enrollment".to_string(),
+            ))
+        }
+        (Some(secret), None) => secret,
+    };
+
+    let totp = Totp::from_base32(&secret)?;
+    if !accept_totp_code(&pool, uuid, &totp, user.totp_last_step, &request_body.code).await? {
+        return Err(ApiError::Unauthorized("Invalid TOTP code".to_string()));
+    }
+
+    query!(
+        "UPDATE users SET totp_enabled_at = CURRENT_TIMESTAMP WHERE uuid = ?",
+        uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    let recovery_codes = replace_recovery_codes(&pool, uuid).await?;
+
+    AuditEntry::new("user.mfa_enable")
+        .actor(uuid, principal.api_key_uuid.as_deref())
+        .target(format!("user:{}", uuid))
+        .diff(json!({ "totp_enabled_at": "set", "recovery_codes": "replaced" }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        Some(RecoveryCodes { recovery_codes }),
+        Some("TOTP enabled successfully, store the recovery codes safely".to_string()),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+struct MfaConfirmation {
+    password: String,
+    // A TOTP code or a recovery code
+    code: String,
+}
+
+#[delete("/user/mfa/totp")]
+async fn disable_totp(
+    principal: Principal,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    context: RequestContext,
+    request_body: Json<MfaConfirmation>,
+) -> Result<ApiResponse<()>, ApiError> {
+    principal.require_personal()?;
+
+    let uuid = &principal.user_uuid;
+
+    // Both factors are needed to remove one of them
+    confirm_password(
+        &pool,
+        &config.auth.password_hash,
+        uuid,
+        &request_body.password,
+    )
+    .await?;
+    require_mfa_code(&pool, uuid, &request_body.code).await?;
+
+    query!(
+        "
+        UPDATE users SET totp_secret = NULL, totp_last_step = NULL, totp_enabled_at = NULL
+        WHERE uuid = ?
+        ",
+        uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+    query!("DELETE FROM mfa_recovery_codes WHERE user_uuid = ?", uuid)
+        .execute(pool.get_pool())
+        .await?;
+
+    AuditEntry::new("user.mfa_disable")
+        .actor(uuid, principal.api_key_uuid.as_deref())
+        .target(format!("user:{}", uuid))
+        .diff(json!({ "totp_enabled_at": "unset", "recovery_codes": "deleted" }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        None,
+        Some("TOTP disabled successfully".to_string()),
+    ))
+}
+
+#[post("/user/mfa/recovery-codes")]
+async fn regenerate_recovery_codes(
+    principal: Principal,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    context: RequestContext,
+    request_body: Json<MfaConfirmation>,
+) -> Result<ApiResponse<RecoveryCodes>, ApiError> {
+    principal.require_personal()?;
+
+    let uuid = &principal.user_uuid;
+
+    confirm_password(
+        &pool,
+        &config.auth.password_hash,
+        uuid,
+        &request_body.password,
+    )
+    .await?;
+    require_mfa_code(&pool, uuid, &request_body.code).await?;
+
+    let recovery_codes = replace_recovery_codes(&pool, uuid).await?;
+
+    AuditEntry::new("user.mfa_recovery_codes")
+        .actor(uuid, principal.api_key_uuid.as_deref())
+        .target(format!("user:{}", uuid))
+        .diff(json!({ "recovery_codes": "replaced" }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        Some(RecoveryCodes { recovery_codes }),
+        Some("Recovery codes replaced, store them safely".to_string()),
+    ))
+}
+
+// The current password of the user, asked again before changing how they sign in
+async fn confirm_password(
+    pool: &DbPool,
+    password_hash_config: &PasswordHashConfig,
+    user_uuid: &str,
+    password: &str,
+) -> Result<(), ApiError> {
+    let user = query!("SELECT password_hash FROM users WHERE uuid = ?", user_uuid)
+        .fetch_one(pool.get_pool())
+        .await?;
+
+    match Password::existing(password).verify(&user.password_hash, password_hash_config)? {
+        true => Ok(()),
+        false => Err(ApiError::Unauthorized("Invalid credentials".to_string())),
+    }
+}
+
+// Fails unless the user has TOTP enabled and the code is a valid TOTP or recovery code
+async fn require_mfa_code(pool: &DbPool, user_uuid: &str, code: &str) -> Result<(), ApiError> {
+    let user = query!(
+        "SELECT totp_enabled_at FROM users WHERE uuid = ?",
+        user_uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    if user.totp_enabled_at.is_none() {
+        return Err(ApiError::InvalidInput("TOTP is not enabled".to_string()));
+    }
+
+    match verify_mfa_code(pool, user_uuid, code).await? {
+        true => Ok(()),
+        false => Err(ApiError::Unauthorized("Invalid MFA code".to_string())),
+    }
+}
+
+// Check a TOTP code of the user, or else one of their recovery codes. Either is only accepted once
+pub(super) async fn verify_mfa_code(
+    pool: &DbPool,
+    user_uuid: &str,
+    code: &str,
+) -> Result<bool, ApiError> {
+    let user = query!(
+        "SELECT totp_secret, totp_last_step FROM users WHERE uuid = ?",
+        user_uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    if let Some(secret) = &user.totp_secret {
+        let totp = Totp::from_base32(secret)?;
+        if accept_totp_code(pool, user_uuid, &totp, user.totp_last_step, code).await? {
+            return Ok(true);
+        }
+    }
+
+    consume_recovery_code(pool, user_uuid, code).await
+}
+
+// Record the time step of a valid TOTP code, a concurrent request using the same code loses the race
+async fn accept_totp_code(
+    pool: &DbPool,
+    user_uuid: &str,
+    totp: &Totp,
+    last_step: Option<i64>,
+    code: &str,
+) -> Result<bool, ApiError> {
+    let Some(step) = totp.verify(code, last_step) else {
+        return Ok(false);
+    };
+
+    let result = query!(
+        "
+        UPDATE users SET totp_last_step = ?
+        WHERE uuid = ? AND (totp_last_step IS NULL OR totp_last_step < ?)
+        ",
+        step,
+        user_uuid,
+        step
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+// Mark a recovery code of the user as used, returns false if it does not exist or was already used
+async fn consume_recovery_code(
+    pool: &DbPool,
+    user_uuid: &str,
+    code: &str,
+) -> Result<bool, ApiError> {
+    // Anything that is not shaped like a recovery code simply does not match
+    let Ok(recovery_code) = RecoveryCode::new(code) else {
+        return Ok(false);
+    };
+    let code_hash = recovery_code.hash();
+
+    let result = query!(
+        "
+        UPDATE mfa_recovery_codes SET used_at = CURRENT_TIMESTAMP
+        WHERE user_uuid = ? AND code_hash = ? AND used_at IS NULL
+        ",
+        user_uuid,
+        code_hash
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+// Replace the recovery codes of the user, only their hashes are stored so they are returned once
+async fn replace_recovery_codes(pool: &DbPool, user_uuid: &str) -> Result<Vec<String>, ApiError> {
+    query!(
+        "DELETE FROM mfa_recovery_codes WHERE user_uuid = ?",
+        user_uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    let mut recovery_codes = Vec::with_capacity(RECOVERY_CODES_COUNT);
+    for _ in 0..RECOVERY_CODES_COUNT {
+        let uuid = Uuid::new_v4().to_string();
+        let recovery_code = RecoveryCode::generate();
+        let code_hash = recovery_code.hash();
+
+        query!(
+            "INSERT INTO mfa_recovery_codes (uuid, user_uuid, code_hash) VALUES (?, ?, ?)",
+            uuid,
+            user_uuid,
+            code_hash,
+        )
+        .execute(pool.get_pool())
+        .await?;
+
+        recovery_codes.push(recovery_code.into_string());
+    }
+
+    Ok(recovery_codes)
+}
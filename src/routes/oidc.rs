@@ -0,0 +1,239 @@
+use super::authentification::{issue_credentials, start_mfa_challenge, SignInResponse};
+use crate::{
+    audit::AuditEntry,
+    config::{Config, OidcConfig},
+    db::DbPool,
+    errors::ApiError,
+    oidc::{IdTokenClaims, OidcClient},
+    responses::ApiResponse,
+    utils::{
+        auth::{Email, JwtKeys, Token},
+        request::RequestContext,
+    },
+};
+use actix_web::{
+    get,
+    web::{Data, Query},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::query;
+use uuid::Uuid;
+
+fn oidc_config(config: &Config) -> Result<&OidcConfig, ApiError> {
+    config
+        .oidc
+        .as_ref()
+        .ok_or_else(|| ApiError::InvalidInput("OIDC login is not configured".to_string()))
+}
+
+#[derive(Serialize, Debug)]
+struct OidcAuthorization {
+    authorization_url: String,
+}
+
+#[get("/auth/oidc/authorize")]
+async fn oidc_authorize(
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    client: Data<OidcClient>,
+) -> Result<ApiResponse<OidcAuthorization>, ApiError> {
+    let oidc_config = oidc_config(&config)?;
+
+    // The state identifies the login attempt, the nonce binds the ID token to it and the verifier proves PKCE
+    let state = Token::generate();
+    let nonce = Token::generate();
+    let code_verifier = Token::generate();
+
+    let uuid = Uuid::new_v4().to_string();
+    let state_hash = state.hash();
+    query!(
+        "
+        INSERT INTO oidc_login_attempts (uuid, state_hash, nonce, code_verifier)
+        VALUES (?, ?, ?, ?)
+        ",
+        uuid,
+        state_hash,
+        nonce.as_str(),
+        code_verifier.as_str(),
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    let authorization_url = client
+        .authorization_url(
+            oidc_config,
+            state.as_str(),
+            nonce.as_str(),
+            code_verifier.as_str(),
+        )
+        .await?;
+
+    Ok(ApiResponse::new(
+        Some(OidcAuthorization { authorization_url }),
+        Some("Redirect the user to the authorization URL to sign in".to_string()),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+struct OidcCallback {
+    code: String,
+    state: String,
+}
+
+#[get("/auth/oidc/callback")]
+async fn oidc_callback(
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    client: Data<OidcClient>,
+    jwt_keys: Option<Data<JwtKeys>>,
+    context: RequestContext,
+    query_params: Query<OidcCallback>,
+) -> Result<ApiResponse<SignInResponse>, ApiError> {
+    let oidc_config = oidc_config(&config)?;
+
+    let state = Token::new(&query_params.state)?;
+    let state_hash = state.hash();
+
+    // Marking the attempt as used in a single statement guarantees a state can only be redeemed once
+    let result = query!(
+        "
+        UPDATE oidc_login_attempts SET used_at = CURRENT_TIMESTAMP
+        WHERE state_hash = ? AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        ",
+        state_hash
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Unauthorized(
+            "Invalid or expired state".to_string(),
+        ));
+    }
+
+    let login_attempt = query!(
+        "SELECT nonce, code_verifier FROM oidc_login_attempts WHERE state_hash = ?",
+        state_hash
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    let claims = client
+        .exchange_code(
+            oidc_config,
+            &query_params.code,
+            &login_attempt.code_verifier,
+            &login_attempt.nonce,
+        )
+        .await?;
+
+    let user_uuid = link_identity(&pool, &context, &oidc_config.issuer, &claims).await?;
+
+    // The identity provider only replaces the password, the second factor is still asked for
+    let user = query!(
+        "SELECT totp_enabled_at FROM users WHERE uuid = ?",
+        &user_uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    if user.totp_enabled_at.is_some() {
+        let challenge = start_mfa_challenge(&pool, &user_uuid).await?;
+
+        return Ok(ApiResponse::new(
+            Some(challenge),
+            Some("Identity verified, a TOTP or recovery code is required".to_string()),
+        ));
+    }
+
+    // Generate and store the credentials
+    let credentials = issue_credentials(
+        &pool,
+        &config,
+        jwt_keys.as_ref().map(Data::get_ref),
+        &context,
+        &user_uuid,
+    )
+    .await?;
+
+    AuditEntry::new("auth.sign_in")
+        .actor(&user_uuid, None)
+        .diff(json!({ "method": "oidc", "issuer": oidc_config.issuer }))
+        .record(&pool, &context)
+        .await?;
+
+    let message = format!(
+        "Identity verified, {} generated successfully",
+        credentials.credentials_name()
+    );
+    Ok(ApiResponse::new(Some(credentials), Some(message)))
+}
+
+// Find the user of an external identity, linking it by verified email on its first login
+async fn link_identity(
+    pool: &DbPool,
+    context: &RequestContext,
+    issuer: &str,
+    claims: &IdTokenClaims,
+) -> Result<String, ApiError> {
+    let identity = query!(
+        "
+        SELECT user_identities.user_uuid
+        FROM user_identities JOIN users ON users.uuid = user_identities.user_uuid
+        WHERE user_identities.issuer = ? AND user_identities.subject = ? AND users.deleted_at IS NULL
+        ",
+        issuer,
+        &claims.sub
+    )
+    .fetch_optional(pool.get_pool())
+    .await?;
+
+    if let Some(identity) = identity {
+        return Ok(identity.user_uuid);
+    }
+
+    // An unverified email could be claimed by anyone at the identity provider
+    let no_account = || ApiError::Unauthorized("No account is linked to this identity".to_string());
+    let typed_email = match (&claims.email, claims.email_verified) {
+        (Some(email), true) => email.trim(),
+        _ => return Err(no_account()),
+    };
+    let email = Email::new(typed_email, false).map_err(|_| no_account())?;
+
+    // Nor is an address nobody confirmed owning here, someone could have signed up with it beforehand
+    let user = query!(
+        "
+        SELECT uuid FROM users
+        WHERE (email_canonical = ? OR (email_canonical IS NULL AND email = BINARY ?))
+            AND verified_at IS NOT NULL AND deleted_at IS NULL
+        ORDER BY email_canonical IS NULL DESC LIMIT 1
+        ",
+        email.canonical(),
+        typed_email
+    )
+    .fetch_optional(pool.get_pool())
+    .await?
+    .ok_or_else(no_account)?;
+
+    let uuid = Uuid::new_v4().to_string();
+    query!(
+        "INSERT INTO user_identities (uuid, user_uuid, issuer, subject) VALUES (?, ?, ?, ?)",
+        uuid,
+        &user.uuid,
+        issuer,
+        &claims.sub
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    AuditEntry::new("user.identity_link")
+        .actor(&user.uuid, None)
+        .target(format!("user_identity:{}", uuid))
+        .diff(json!({ "issuer": issuer, "subject": claims.sub }))
+        .record(pool, context)
+        .await?;
+
+    Ok(user.uuid)
+}
@@ -0,0 +1,633 @@
+use super::authentification::{store_api_key, ApiKeyResponse};
+use crate::{
+    audit::AuditEntry,
+    config::Config,
+    db::DbPool,
+    errors::ApiError,
+    mail::{Mail, Mailer},
+    middleware::{api_key::Principal, api_key_cache::ApiKeyCache},
+    responses::ApiResponse,
+    utils::{
+        auth::{ApiKey, Email, Token},
+        request::RequestContext,
+    },
+};
+use actix_web::{
+    delete, get, post, put,
+    web::{Data, Json, Path},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{query, query_as, types::chrono::NaiveDateTime, MySqlConnection};
+use uuid::Uuid;
+
+// Role of a member within an organization, ordered from the least to the most privileged
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum OrganizationRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+impl OrganizationRole {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Member => "member",
+            Self::Admin => "admin",
+            Self::Owner => "owner",
+        }
+    }
+
+    fn parse(role: &str) -> Result<Self, ApiError> {
+        match role {
+            "member" => Ok(Self::Member),
+            "admin" => Ok(Self::Admin),
+            "owner" => Ok(Self::Owner),
+            _ => Err(ApiError::InvalidInput(format!(
+                "Unknown organization role: {}",
+                role
+            ))),
+        }
+    }
+}
+
+// Fail unless the principal has at least the required role in the organization
+async fn require_role(
+    pool: &DbPool,
+    principal: &Principal,
+    organization_uuid: &str,
+    required_role: OrganizationRole,
+) -> Result<(), ApiError> {
+    // A key acting on behalf of an organization cannot reach into another one
+    if let Some(key_organization_uuid) = &principal.organization_uuid {
+        if key_organization_uuid != organization_uuid {
+            return Err(ApiError::Forbidden(
+                "Credentials act on behalf of another organization".to_string(),
+            ));
+        }
+    }
+
+    let member = query!(
+        "SELECT role FROM organization_members WHERE organization_uuid = ? AND user_uuid = ?",
+        organization_uuid,
+        &principal.user_uuid
+    )
+    .fetch_optional(pool.get_pool())
+    .await?
+    .ok_or_else(|| ApiError::Forbidden("Not a member of this organization".to_string()))?;
+
+    let role = OrganizationRole::parse(&member.role)?;
+    if role < required_role {
+        return Err(ApiError::Forbidden(format!(
+            "Organization {} role required",
+            required_role.as_str()
+        )));
+    }
+
+    Ok(())
+}
+
+// The owners of the organization, locked until the transaction ends so that concurrent
+// demotions or removals cannot leave it without any
+async fn lock_owners(
+    connection: &mut MySqlConnection,
+    organization_uuid: &str,
+) -> Result<Vec<String>, ApiError> {
+    let owners = query!(
+        "
+        SELECT user_uuid FROM organization_members
+        WHERE organization_uuid = ? AND role = 'owner'
+        FOR UPDATE
+        ",
+        organization_uuid
+    )
+    .fetch_all(connection)
+    .await?;
+
+    Ok(owners.into_iter().map(|owner| owner.user_uuid).collect())
+}
+
+#[derive(Deserialize, Debug)]
+struct NewOrganization {
+    name: String,
+}
+
+#[derive(Serialize, Debug)]
+struct OrganizationMetadata {
+    uuid: String,
+    name: String,
+    role: String,
+    created_at: NaiveDateTime,
+}
+
+#[post("/orgs")]
+async fn create_organization(
+    principal: Principal,
+    pool: Data<DbPool>,
+    context: RequestContext,
+    request_body: Json<NewOrganization>,
+) -> Result<ApiResponse<OrganizationMetadata>, ApiError> {
+    let name = request_body.name.trim();
+    if name.is_empty() || name.chars().count() > 255 {
+        return Err(ApiError::InvalidInput(
+            "Organization name must be between 1 and 255 characters long".to_string(),
+        ));
+    }
+
+    // Keys acting on behalf of an organization cannot found new ones
+    principal.require_personal()?;
+
+    let uuid = Uuid::new_v4().to_string();
+
+    // The creator becomes the first owner
+    let mut transaction = pool.get_pool().begin().await?;
+    query!(
+        "INSERT INTO organizations (uuid, name) VALUES (?, ?)",
+        uuid,
+        name
+    )
+    .execute(&mut *transaction)
+    .await?;
+    query!(
+        "INSERT INTO organization_members (organization_uuid, user_uuid, role) VALUES (?, ?, 'owner')",
+        uuid,
+        &principal.user_uuid
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    AuditEntry::new("organization.create")
+        .actor(&principal.user_uuid, principal.api_key_uuid.as_deref())
+        .target(format!("organization:{}", uuid))
+        .diff(json!({ "name": name }))
+        .record(&pool, &context)
+        .await?;
+
+    let organization = query_as!(
+        OrganizationMetadata,
+        "
+        SELECT organizations.uuid, organizations.name, organization_members.role,
+            organizations.created_at
+        FROM organizations
+        JOIN organization_members ON organization_members.organization_uuid = organizations.uuid
+        WHERE organizations.uuid = ? AND organization_members.user_uuid = ?
+        ",
+        uuid,
+        &principal.user_uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    Ok(ApiResponse::new(
+        Some(organization),
+        Some("Organization created successfully".to_string()),
+    ))
+}
+
+#[get("/orgs")]
+async fn list_organizations(
+    principal: Principal,
+    pool: Data<DbPool>,
+) -> Result<ApiResponse<Vec<OrganizationMetadata>>, ApiError> {
+    let organizations = query_as!(
+        OrganizationMetadata,
+        "
+        SELECT organizations.uuid, organizations.name, organization_members.role,
+            organizations.created_at
+        FROM organizations
+        JOIN organization_members ON organization_members.organization_uuid = organizations.uuid
+        WHERE organization_members.user_uuid = ? AND (? IS NULL OR organizations.uuid = ?)
+        ORDER BY organizations.created_at, organizations.uuid
+        ",
+        &principal.user_uuid,
+        &principal.organization_uuid,
+        &principal.organization_uuid
+    )
+    .fetch_all(pool.get_pool())
+    .await?;
+
+    let message = format!("{} organizations retrieved", organizations.len());
+    Ok(ApiResponse::new(Some(organizations), Some(message)))
+}
+
+#[derive(Serialize, Debug)]
+struct OrganizationMember {
+    user_uuid: String,
+    email: String,
+    role: String,
+    created_at: NaiveDateTime,
+}
+
+#[get("/orgs/{organization_uuid}/members")]
+async fn list_members(
+    principal: Principal,
+    pool: Data<DbPool>,
+    path: Path<String>,
+) -> Result<ApiResponse<Vec<OrganizationMember>>, ApiError> {
+    let organization_uuid = path.into_inner();
+    require_role(
+        &pool,
+        &principal,
+        &organization_uuid,
+        OrganizationRole::Member,
+    )
+    .await?;
+
+    let members = query_as!(
+        OrganizationMember,
+        "
+        SELECT organization_members.user_uuid, users.email, organization_members.role,
+            organization_members.created_at
+        FROM organization_members JOIN users ON users.uuid = organization_members.user_uuid
+        WHERE organization_members.organization_uuid = ?
+        ORDER BY organization_members.created_at, organization_members.user_uuid
+        ",
+        organization_uuid
+    )
+    .fetch_all(pool.get_pool())
+    .await?;
+
+    let message = format!("{} organization members retrieved", members.len());
+    Ok(ApiResponse::new(Some(members), Some(message)))
+}
+
+#[derive(Deserialize, Debug)]
+struct NewInvitation {
+    email: String,
+    role: OrganizationRole,
+}
+
+#[derive(Serialize, Debug)]
+struct InvitationMetadata {
+    uuid: String,
+    email: String,
+    role: String,
+    expires_at: NaiveDateTime,
+}
+
+#[post("/orgs/{organization_uuid}/invitations")]
+async fn invite_member(
+    principal: Principal,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    mailer: Data<Mailer>,
+    context: RequestContext,
+    path: Path<String>,
+    request_body: Json<NewInvitation>,
+) -> Result<ApiResponse<InvitationMetadata>, ApiError> {
+    let organization_uuid = path.into_inner();
+    let email = Email::new(&request_body.email, config.auth.lowercase_email_local_part)
+        .map_err(|e| e.at("/email"))?;
+
+    // Admins invite members, only owners invite admins, ownership is granted by an owner afterwards
+    let required_role = match request_body.role {
+        OrganizationRole::Member => OrganizationRole::Admin,
+        OrganizationRole::Admin => OrganizationRole::Owner,
+        OrganizationRole::Owner => {
+            return Err(ApiError::InvalidInput(
+                "Invitations are limited to the admin and member roles".to_string(),
+            ))
+        }
+    };
+    require_role(&pool, &principal, &organization_uuid, required_role).await?;
+
+    let organization = query!(
+        "SELECT name FROM organizations WHERE uuid = ?",
+        organization_uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    let uuid = Uuid::new_v4().to_string();
+    let token = Token::generate();
+    let token_hash = token.hash();
+
+    // The expiry is set by the database
+    query!(
+        "
+        INSERT INTO organization_invitations
+            (uuid, organization_uuid, email, email_canonical, role, token_hash, invited_by)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        ",
+        uuid,
+        organization_uuid,
+        email.as_str(),
+        email.canonical(),
+        request_body.role.as_str(),
+        token_hash,
+        &principal.user_uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    let invitation = query_as!(
+        InvitationMetadata,
+        "SELECT uuid, email, role, expires_at FROM organization_invitations WHERE uuid = ?",
+        uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    AuditEntry::new("organization.invite")
+        .actor(&principal.user_uuid, principal.api_key_uuid.as_deref())
+        .target(format!("organization:{}", organization_uuid))
+        .diff(json!({ "email": invitation.email, "role": invitation.role }))
+        .record(&pool, &context)
+        .await?;
+
+    mailer.send_detached(Mail {
+        to: invitation.email.clone(),
+        subject: format!("Join {} on http2sql", organization.name),
+        body: format!(
+            "You have been invited to join {} as {}. Sign in with this email address and use the following token to accept, it is valid for 7 days and can only be used once:\n\n{}",
+            organization.name,
+            invitation.role,
+            token.as_str()
+        ),
+    });
+
+    Ok(ApiResponse::new(
+        Some(invitation),
+        Some("Invitation sent successfully".to_string()),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+struct AcceptInvitation {
+    token: String,
+}
+
+#[post("/orgs/invitations/accept")]
+async fn accept_invitation(
+    principal: Principal,
+    pool: Data<DbPool>,
+    context: RequestContext,
+    request_body: Json<AcceptInvitation>,
+) -> Result<ApiResponse<OrganizationMetadata>, ApiError> {
+    let token = Token::new(&request_body.token)?;
+    let token_hash = token.hash();
+
+    // Only the invited address can accept, whatever its case, and a single statement guarantees it is accepted once.
+    // Invitations and accounts that predate canonical addresses need the exact same address
+    let result = query!(
+        "
+        UPDATE organization_invitations SET accepted_at = CURRENT_TIMESTAMP
+        WHERE token_hash = ? AND accepted_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+            AND (
+                email_canonical = (SELECT email_canonical FROM users WHERE uuid = ?)
+                OR email = (SELECT email FROM users WHERE uuid = ?)
+            )
+        ",
+        token_hash,
+        &principal.user_uuid,
+        &principal.user_uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Unauthorized(
+            "Invalid or expired invitation".to_string(),
+        ));
+    }
+
+    let invitation = query!(
+        "SELECT organization_uuid, role FROM organization_invitations WHERE token_hash = ?",
+        token_hash
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    // Existing members keep their current role
+    query!(
+        "INSERT IGNORE INTO organization_members (organization_uuid, user_uuid, role) VALUES (?, ?, ?)",
+        &invitation.organization_uuid,
+        &principal.user_uuid,
+        &invitation.role
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    AuditEntry::new("organization.join")
+        .actor(&principal.user_uuid, principal.api_key_uuid.as_deref())
+        .target(format!("organization:{}", invitation.organization_uuid))
+        .diff(json!({ "role": invitation.role }))
+        .record(&pool, &context)
+        .await?;
+
+    let organization = query_as!(
+        OrganizationMetadata,
+        "
+        SELECT organizations.uuid, organizations.name, organization_members.role,
+            organizations.created_at
+        FROM organizations
+        JOIN organization_members ON organization_members.organization_uuid = organizations.uuid
+        WHERE organizations.uuid = ? AND organization_members.user_uuid = ?
+        ",
+        &invitation.organization_uuid,
+        &principal.user_uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    Ok(ApiResponse::new(
+        Some(organization),
+        Some("Invitation accepted successfully".to_string()),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+struct MemberUpdate {
+    role: OrganizationRole,
+}
+
+#[put("/orgs/{organization_uuid}/members/{user_uuid}")]
+async fn update_member(
+    principal: Principal,
+    pool: Data<DbPool>,
+    context: RequestContext,
+    path: Path<(String, String)>,
+    request_body: Json<MemberUpdate>,
+) -> Result<ApiResponse<()>, ApiError> {
+    let (organization_uuid, user_uuid) = path.into_inner();
+    require_role(
+        &pool,
+        &principal,
+        &organization_uuid,
+        OrganizationRole::Owner,
+    )
+    .await?;
+
+    let member = query!(
+        "SELECT role FROM organization_members WHERE organization_uuid = ? AND user_uuid = ?",
+        organization_uuid,
+        user_uuid
+    )
+    .fetch_optional(pool.get_pool())
+    .await?
+    .ok_or_else(|| ApiError::NotFound("User is not a member of this organization".to_string()))?;
+
+    // An organization always keeps at least one owner
+    let mut transaction = pool.get_pool().begin().await?;
+    let owners = lock_owners(&mut transaction, &organization_uuid).await?;
+    if owners == [user_uuid.as_str()] && request_body.role != OrganizationRole::Owner {
+        return Err(ApiError::InvalidInput(
+            "The last owner of an organization cannot be demoted".to_string(),
+        ));
+    }
+
+    query!(
+        "UPDATE organization_members SET role = ? WHERE organization_uuid = ? AND user_uuid = ?",
+        request_body.role.as_str(),
+        organization_uuid,
+        user_uuid
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    AuditEntry::new("organization.member_update")
+        .actor(&principal.user_uuid, principal.api_key_uuid.as_deref())
+        .target(format!("organization:{}", organization_uuid))
+        .diff(json!({
+            "user_uuid": user_uuid,
+            "role": { "from": member.role, "to": request_body.role.as_str() },
+        }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        None,
+        Some("Organization member updated successfully".to_string()),
+    ))
+}
+
+#[derive(Serialize, Debug)]
+struct MemberRemovalResponse {
+    revoked_api_keys: u64,
+}
+
+#[delete("/orgs/{organization_uuid}/members/{user_uuid}")]
+async fn remove_member(
+    principal: Principal,
+    pool: Data<DbPool>,
+    api_key_cache: Data<ApiKeyCache>,
+    context: RequestContext,
+    path: Path<(String, String)>,
+) -> Result<ApiResponse<MemberRemovalResponse>, ApiError> {
+    let (organization_uuid, user_uuid) = path.into_inner();
+
+    let member = query!(
+        "SELECT role FROM organization_members WHERE organization_uuid = ? AND user_uuid = ?",
+        organization_uuid,
+        user_uuid
+    )
+    .fetch_optional(pool.get_pool())
+    .await?;
+    let member_role = match member {
+        Some(member) => OrganizationRole::parse(&member.role)?,
+        None => OrganizationRole::Member,
+    };
+
+    // Anyone may leave, removing others takes an admin, and an owner to remove another admin or owner
+    let required_role = match (user_uuid == principal.user_uuid, member_role) {
+        (true, _) => OrganizationRole::Member,
+        (false, OrganizationRole::Member) => OrganizationRole::Admin,
+        (false, _) => OrganizationRole::Owner,
+    };
+    require_role(&pool, &principal, &organization_uuid, required_role).await?;
+
+    let mut transaction = pool.get_pool().begin().await?;
+    let owners = lock_owners(&mut transaction, &organization_uuid).await?;
+    if owners == [user_uuid.as_str()] {
+        return Err(ApiError::InvalidInput(
+            "The last owner of an organization cannot be removed".to_string(),
+        ));
+    }
+
+    let result = query!(
+        "DELETE FROM organization_members WHERE organization_uuid = ? AND user_uuid = ?",
+        organization_uuid,
+        user_uuid
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(
+            "User is not a member of this organization".to_string(),
+        ));
+    }
+
+    // Keys acting on behalf of the organization leave with their user
+    let revoked_api_keys = query!(
+        "DELETE FROM api_keys WHERE user_uuid = ? AND organization_uuid = ?",
+        user_uuid,
+        organization_uuid
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    query!(
+        "DELETE FROM signing_keys WHERE user_uuid = ? AND organization_uuid = ?",
+        user_uuid,
+        organization_uuid
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    api_key_cache.invalidate_user(&user_uuid);
+
+    AuditEntry::new("organization.member_remove")
+        .actor(&principal.user_uuid, principal.api_key_uuid.as_deref())
+        .target(format!("organization:{}", organization_uuid))
+        .diff(json!({ "user_uuid": user_uuid, "revoked_api_keys": revoked_api_keys }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        Some(MemberRemovalResponse { revoked_api_keys }),
+        Some("Organization member removed successfully".to_string()),
+    ))
+}
+
+#[post("/orgs/{organization_uuid}/api-keys")]
+async fn create_organization_api_key(
+    principal: Principal,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    context: RequestContext,
+    path: Path<String>,
+) -> Result<ApiResponse<ApiKeyResponse>, ApiError> {
+    let organization_uuid = path.into_inner();
+    require_role(
+        &pool,
+        &principal,
+        &organization_uuid,
+        OrganizationRole::Member,
+    )
+    .await?;
+
+    let api_key = ApiKey::generate(&config.auth.api_key_format);
+    let api_key_metadata = store_api_key(
+        &pool,
+        &config.auth.api_key_hash,
+        &context,
+        &principal.user_uuid,
+        Some(&organization_uuid),
+        &api_key,
+    )
+    .await?;
+
+    Ok(ApiResponse::new(
+        Some(ApiKeyResponse {
+            api_key: api_key.into_string(),
+            created_at: api_key_metadata.created_at,
+            expires_at: api_key_metadata.expires_at,
+        }),
+        Some("Organization API key generated successfully".to_string()),
+    ))
+}
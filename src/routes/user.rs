@@ -1,10 +1,37 @@
 use crate::{
-    db::DbPool, errors::ApiError, middleware::api_key::auth_to_uuid, responses::ApiResponse,
+    account::export_user_data,
+    audit::AuditEntry,
+    config::Config,
+    db::DbPool,
+    errors::ApiError,
+    middleware::{
+        api_key::{normalize_origin, Principal},
+        api_key_cache::ApiKeyCache,
+    },
+    responses::ApiResponse,
+    utils::{
+        auth::{Password, SigningKeySecret, Token},
+        request::{parse_ip_network, RequestContext},
+    },
 };
-use actix_web::{get, web::Data, Result};
-use actix_web_httpauth::extractors::bearer::BearerAuth;
-use serde::Serialize;
-use sqlx::{query_as, types::chrono::NaiveDateTime};
+use actix_web::{
+    delete, get,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    post, put,
+    web::{Data, Json, Path},
+    HttpResponse, Result,
+};
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{
+    query, query_as,
+    types::{chrono::NaiveDateTime, Json as SqlJson},
+};
+use uuid::Uuid;
+
+// Upper bound of the networks and of the origins an API key can be restricted to
+const MAX_API_KEY_RESTRICTIONS: usize = 32;
 
 #[derive(Serialize, Debug)]
 struct UserMetadata {
@@ -15,16 +42,15 @@ struct UserMetadata {
 
 #[get("/user/metadata")]
 async fn get_user_metadata(
-    auth: BearerAuth,
+    principal: Principal,
     pool: Data<DbPool>,
 ) -> Result<ApiResponse<UserMetadata>, ApiError> {
-    // Authenticate the user using the API key and get the user_uuid
-    let uuid = auth_to_uuid(&pool, auth.token()).await?;
+    principal.require_personal()?;
 
     let user_metadata = query_as!(
         UserMetadata,
         "SELECT uuid, email, created_at FROM users WHERE uuid = ?",
-        &uuid,
+        &principal.user_uuid,
     )
     .fetch_one(pool.get_pool())
     .await?;
@@ -34,3 +60,435 @@ async fn get_user_metadata(
         Some("User metadata retrieved successfully".to_string()),
     ))
 }
+
+#[derive(Deserialize, Debug)]
+struct PasswordChange {
+    current_password: String,
+    new_password: String,
+    #[serde(default, alias = "revoke_other_api_keys")]
+    revoke_other_sessions: bool,
+}
+
+#[derive(Serialize, Debug, Default)]
+struct PasswordChangeResponse {
+    revoked_api_keys: u64,
+    revoked_signing_keys: u64,
+    revoked_refresh_tokens: u64,
+}
+
+#[post("/user/password")]
+async fn change_password(
+    principal: Principal,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    api_key_cache: Data<ApiKeyCache>,
+    context: RequestContext,
+    request_body: Json<PasswordChange>,
+) -> Result<ApiResponse<PasswordChangeResponse>, ApiError> {
+    principal.require_personal()?;
+
+    let uuid = &principal.user_uuid;
+    let api_key_uuid = principal.api_key_uuid.as_deref();
+
+    // Validate the new password before touching the database, the current one may predate the policy
+    let current_password = Password::existing(&request_body.current_password);
+    let new_password = Password::new(&request_body.new_password, &config.auth.password_policy)
+        .await
+        .map_err(|e| e.at("/new_password"))?;
+
+    // The current password must match the stored hash
+    let user = query!("SELECT password_hash FROM users WHERE uuid = ?", uuid)
+        .fetch_one(pool.get_pool())
+        .await?;
+
+    if !current_password.verify(&user.password_hash, &config.auth.password_hash)? {
+        return Err(ApiError::Unauthorized("Invalid credentials".to_string()));
+    }
+
+    // Store a freshly salted hash of the new password
+    let new_password_hash = new_password.hash(&config.auth.password_hash)?;
+    query!(
+        "UPDATE users SET password_hash = ? WHERE uuid = ?",
+        new_password_hash,
+        uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    AuditEntry::new("user.password_change")
+        .actor(uuid, api_key_uuid)
+        .target(format!("user:{}", uuid))
+        .diff(json!({ "password_hash": "changed" }))
+        .record(&pool, &context)
+        .await?;
+
+    // Optionally revoke every session except the credential used for this request
+    let revoked = match request_body.revoke_other_sessions {
+        true => revoke_other_sessions(&pool, &principal).await?,
+        false => PasswordChangeResponse::default(),
+    };
+
+    if revoked.revoked_api_keys > 0 {
+        api_key_cache.invalidate_user(uuid);
+    }
+
+    if revoked.revoked_api_keys + revoked.revoked_signing_keys + revoked.revoked_refresh_tokens > 0
+    {
+        AuditEntry::new("api_key.revoke")
+            .actor(uuid, api_key_uuid)
+            .target(format!("user:{}", uuid))
+            .diff(json!({
+                "reason": "password_change",
+                "revoked_api_keys": revoked.revoked_api_keys,
+                "revoked_signing_keys": revoked.revoked_signing_keys,
+                "revoked_refresh_tokens": revoked.revoked_refresh_tokens,
+            }))
+            .record(&pool, &context)
+            .await?;
+    }
+
+    Ok(ApiResponse::new(
+        Some(revoked),
+        Some("Password changed successfully".to_string()),
+    ))
+}
+
+// Revoke the API keys, signing keys and refresh tokens of the user, except the credential of the principal.
+// An access token has no counterpart to keep, it expires on its own
+async fn revoke_other_sessions(
+    pool: &DbPool,
+    principal: &Principal,
+) -> Result<PasswordChangeResponse, ApiError> {
+    let user_uuid = &principal.user_uuid;
+
+    let revoked_api_keys = query!(
+        "DELETE FROM api_keys WHERE user_uuid = ? AND uuid != ?",
+        user_uuid,
+        principal.api_key_uuid.as_deref().unwrap_or_default()
+    )
+    .execute(pool.get_pool())
+    .await?
+    .rows_affected();
+
+    let revoked_signing_keys = query!(
+        "DELETE FROM signing_keys WHERE user_uuid = ? AND uuid != ?",
+        user_uuid,
+        principal.signing_key_uuid.as_deref().unwrap_or_default()
+    )
+    .execute(pool.get_pool())
+    .await?
+    .rows_affected();
+
+    let revoked_refresh_tokens = query!(
+        "UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP WHERE user_uuid = ? AND revoked_at IS NULL",
+        user_uuid
+    )
+    .execute(pool.get_pool())
+    .await?
+    .rows_affected();
+
+    Ok(PasswordChangeResponse {
+        revoked_api_keys,
+        revoked_signing_keys,
+        revoked_refresh_tokens,
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct AccountDeletion {
+    password: String,
+}
+
+#[derive(Serialize, Debug)]
+struct AccountDeletionResponse {
+    purge_after: NaiveDateTime,
+}
+
+#[delete("/user")]
+async fn delete_user(
+    principal: Principal,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    api_key_cache: Data<ApiKeyCache>,
+    context: RequestContext,
+    request_body: Json<AccountDeletion>,
+) -> Result<ApiResponse<AccountDeletionResponse>, ApiError> {
+    principal.require_personal()?;
+
+    let uuid = &principal.user_uuid;
+
+    // Deleting the account requires the password again, a leaked API key is not enough
+    let password = Password::existing(&request_body.password);
+    let user = query!(
+        "SELECT password_hash FROM users WHERE uuid = ? AND deleted_at IS NULL",
+        uuid
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    if !password.verify(&user.password_hash, &config.auth.password_hash)? {
+        return Err(ApiError::Unauthorized("Invalid credentials".to_string()));
+    }
+
+    // The account is only marked as deleted, it is purged once the grace period is over
+    query!(
+        "UPDATE users SET deleted_at = CURRENT_TIMESTAMP WHERE uuid = ? AND deleted_at IS NULL",
+        uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    // Credentials stop working right away
+    let revoked_api_keys = query!("DELETE FROM api_keys WHERE user_uuid = ?", uuid)
+        .execute(pool.get_pool())
+        .await?
+        .rows_affected();
+    api_key_cache.invalidate_user(uuid);
+    query!("DELETE FROM signing_keys WHERE user_uuid = ?", uuid)
+        .execute(pool.get_pool())
+        .await?;
+    query!(
+        "UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP WHERE user_uuid = ? AND revoked_at IS NULL",
+        uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    AuditEntry::new("user.delete")
+        .actor(uuid, principal.api_key_uuid.as_deref())
+        .target(format!("user:{}", uuid))
+        .diff(json!({ "deleted_at": "set", "revoked_api_keys": revoked_api_keys }))
+        .record(&pool, &context)
+        .await?;
+
+    let deleted_user = query!("SELECT deleted_at FROM users WHERE uuid = ?", uuid)
+        .fetch_one(pool.get_pool())
+        .await?;
+    let purge_after = deleted_user.deleted_at.unwrap_or_default()
+        + Duration::days(config.account_deletion_grace_days);
+
+    Ok(ApiResponse::new(
+        Some(AccountDeletionResponse { purge_after }),
+        Some("Account deleted successfully".to_string()),
+    ))
+}
+
+#[get("/user/export")]
+async fn export_user(
+    principal: Principal,
+    pool: Data<DbPool>,
+    context: RequestContext,
+) -> Result<HttpResponse, ApiError> {
+    principal.require_personal()?;
+
+    let uuid = principal.user_uuid;
+
+    AuditEntry::new("user.export")
+        .actor(&uuid, principal.api_key_uuid.as_deref())
+        .target(format!("user:{}", uuid))
+        .record(&pool, &context)
+        .await?;
+
+    let content_disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!(
+            "http2sql-export-{}.json",
+            uuid
+        ))],
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(content_disposition)
+        .streaming(export_user_data(pool.get_ref().clone(), uuid)))
+}
+
+#[derive(Serialize, Debug)]
+struct SigningKeyResponse {
+    key_id: String,
+    secret: String,
+    created_at: NaiveDateTime,
+    expires_at: Option<NaiveDateTime>,
+}
+
+#[post("/user/signing-keys")]
+async fn create_signing_key(
+    principal: Principal,
+    pool: Data<DbPool>,
+    config: Data<Config>,
+    context: RequestContext,
+) -> Result<ApiResponse<SigningKeyResponse>, ApiError> {
+    let key_id = Uuid::new_v4().to_string();
+
+    // The server needs the secret itself to check signatures, so it is stored encrypted rather than hashed
+    let secret = Token::generate();
+    let encrypted = SigningKeySecret::encrypt(
+        secret.as_str(),
+        &key_id,
+        &config.auth.signing_key_encryption,
+    );
+
+    // A key made with credentials acting on behalf of an organization acts on its behalf too
+    query!(
+        "
+        INSERT INTO signing_keys (uuid, user_uuid, organization_uuid, secret, secret_version)
+        VALUES (?, ?, ?, ?, ?)
+        ",
+        key_id,
+        &principal.user_uuid,
+        &principal.organization_uuid,
+        encrypted.stored,
+        encrypted.version
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    let signing_key = query!(
+        "SELECT created_at, expires_at FROM signing_keys WHERE uuid = ?",
+        key_id
+    )
+    .fetch_one(pool.get_pool())
+    .await?;
+
+    AuditEntry::new("signing_key.create")
+        .actor(&principal.user_uuid, principal.api_key_uuid.as_deref())
+        .target(format!("signing_key:{}", key_id))
+        .diff(json!({
+            "organization_uuid": principal.organization_uuid,
+            "expires_at": signing_key.expires_at
+        }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        Some(SigningKeyResponse {
+            key_id,
+            secret: secret.as_str().to_string(),
+            created_at: signing_key.created_at,
+            expires_at: signing_key.expires_at,
+        }),
+        Some("Signing key generated successfully, store the secret safely".to_string()),
+    ))
+}
+
+#[delete("/user/signing-keys/{key_id}")]
+async fn delete_signing_key(
+    principal: Principal,
+    pool: Data<DbPool>,
+    context: RequestContext,
+    path: Path<String>,
+) -> Result<ApiResponse<()>, ApiError> {
+    let key_id = path.into_inner();
+
+    // Credentials acting on behalf of an organization only reach the keys of that organization
+    let result = query!(
+        "
+        DELETE FROM signing_keys
+        WHERE uuid = ? AND user_uuid = ? AND (? IS NULL OR organization_uuid = ?)
+        ",
+        key_id,
+        &principal.user_uuid,
+        &principal.organization_uuid,
+        &principal.organization_uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Unknown signing key".to_string()));
+    }
+
+    AuditEntry::new("signing_key.revoke")
+        .actor(&principal.user_uuid, principal.api_key_uuid.as_deref())
+        .target(format!("signing_key:{}", key_id))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        None,
+        Some("Signing key revoked successfully".to_string()),
+    ))
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ApiKeyRestrictionsRequest {
+    #[serde(default)]
+    allowed_cidrs: Vec<String>,
+    #[serde(default)]
+    allowed_origins: Vec<String>,
+}
+
+// Restrict the API key authenticating the request, from then on it can only lift its own restrictions
+// from where they allow it
+#[put("/user/api-key/restrictions")]
+async fn restrict_api_key(
+    principal: Principal,
+    pool: Data<DbPool>,
+    api_key_cache: Data<ApiKeyCache>,
+    context: RequestContext,
+    request_body: Json<ApiKeyRestrictionsRequest>,
+) -> Result<ApiResponse<ApiKeyRestrictionsRequest>, ApiError> {
+    let Some(api_key_uuid) = &principal.api_key_uuid else {
+        return Err(ApiError::InvalidInput(
+            "Only API keys can be restricted".to_string(),
+        ));
+    };
+
+    if request_body.allowed_cidrs.len() > MAX_API_KEY_RESTRICTIONS
+        || request_body.allowed_origins.len() > MAX_API_KEY_RESTRICTIONS
+    {
+        return Err(ApiError::InvalidInput(format!(
+            "At most {} networks and {} origins are allowed",
+            MAX_API_KEY_RESTRICTIONS, MAX_API_KEY_RESTRICTIONS
+        )));
+    }
+
+    let allowed_cidrs = request_body
+        .allowed_cidrs
+        .iter()
+        .map(|cidr| {
+            parse_ip_network(cidr)
+                .map(|network| network.trunc().to_string())
+                .ok_or_else(|| ApiError::InvalidInput(format!("Invalid CIDR range: {}", cidr)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let allowed_origins = request_body
+        .allowed_origins
+        .iter()
+        .map(|origin| {
+            normalize_origin(origin)
+                .ok_or_else(|| ApiError::InvalidInput(format!("Invalid origin: {}", origin)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Empty lists lift the restriction
+    let stored_cidrs = (!allowed_cidrs.is_empty()).then_some(SqlJson(&allowed_cidrs));
+    let stored_origins = (!allowed_origins.is_empty()).then_some(SqlJson(&allowed_origins));
+
+    query!(
+        "UPDATE api_keys SET allowed_cidrs = ?, allowed_origins = ? WHERE uuid = ?",
+        stored_cidrs,
+        stored_origins,
+        api_key_uuid
+    )
+    .execute(pool.get_pool())
+    .await?;
+
+    api_key_cache.invalidate_user(&principal.user_uuid);
+
+    AuditEntry::new("api_key.restrict")
+        .actor(&principal.user_uuid, Some(api_key_uuid.as_str()))
+        .target(format!("api_key:{}", api_key_uuid))
+        .diff(json!({ "allowed_cidrs": allowed_cidrs, "allowed_origins": allowed_origins }))
+        .record(&pool, &context)
+        .await?;
+
+    Ok(ApiResponse::new(
+        Some(ApiKeyRestrictionsRequest {
+            allowed_cidrs,
+            allowed_origins,
+        }),
+        Some("API key restrictions updated successfully".to_string()),
+    ))
+}
@@ -0,0 +1,178 @@
+use crate::{config::TlsConfig, errors::ApiError};
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use sha2::{Digest, Sha256};
+use std::{any::Any, fs::File, io::BufReader, path::Path, sync::Arc};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// The end-entity certificate a client presented during the TLS handshake, already verified against the client CA
+#[derive(Debug, Clone)]
+pub struct ClientCertificate(pub Vec<u8>);
+
+impl ClientCertificate {
+    /// The names the certificate can be mapped with, subject alternative names first, e.g.
+    /// `uri:spiffe://mesh.local/billing`, `dns:billing.mesh.local`, `email:ops@example.com` or `cn:billing`
+    pub fn identities(&self) -> Result<Vec<String>, ApiError> {
+        let (_, certificate) = X509Certificate::from_der(&self.0)
+            .map_err(|_| ApiError::Unauthorized("Invalid client certificate".to_string()))?;
+
+        let mut identities = Vec::new();
+
+        if let Ok(Some(subject_alternative_name)) = certificate.subject_alternative_name() {
+            for name in &subject_alternative_name.value.general_names {
+                match name {
+                    GeneralName::URI(uri) => identities.push(format!("uri:{}", uri)),
+                    GeneralName::DNSName(dns) => identities.push(format!("dns:{}", dns)),
+                    GeneralName::RFC822Name(email) => identities.push(format!("email:{}", email)),
+                    _ => (),
+                }
+            }
+        }
+
+        for common_name in certificate.subject().iter_common_name() {
+            if let Ok(common_name) = common_name.as_str() {
+                identities.push(format!("cn:{}", common_name));
+            }
+        }
+
+        Ok(identities)
+    }
+
+    /// Hex SHA-256 of the DER encoding, identifies the client without parsing the certificate
+    pub fn fingerprint(&self) -> String {
+        format!("{:x}", Sha256::digest(&self.0))
+    }
+}
+
+/// Keep the client certificate of TLS connections, to be registered with `HttpServer::on_connect`
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+
+    let (_, session) = stream.get_ref();
+    if let Some(certificate) = session.peer_certificates().and_then(|chain| chain.first()) {
+        extensions.insert(ClientCertificate(certificate.to_vec()));
+    }
+}
+
+/// Build the rustls configuration, requesting client certificates when a client CA is configured
+pub fn server_config(config: &TlsConfig) -> Result<ServerConfig, ApiError> {
+    let provider = Arc::new(default_provider());
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| ApiError::ConfigError(format!("Invalid TLS protocol versions: {}", e)))?;
+
+    let builder = match &config.client_ca_file {
+        Some(client_ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(client_ca_file)? {
+                roots.add(certificate).map_err(|e| {
+                    ApiError::ConfigError(format!("Invalid client CA certificate: {}", e))
+                })?;
+            }
+
+            // Without a certificate, clients fall back to the other authentication schemes
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match config.require_client_certificate {
+                true => verifier,
+                false => verifier.allow_unauthenticated(),
+            };
+            let verifier = verifier.build().map_err(|e| {
+                ApiError::ConfigError(format!("Invalid client certificate verifier: {}", e))
+            })?;
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(
+            read_certificates(&config.cert_file)?,
+            read_private_key(&config.key_file)?,
+        )
+        .map_err(|e| ApiError::ConfigError(format!("Invalid TLS certificate or key: {}", e)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, ApiError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| ApiError::ConfigError(format!("Failed to read {}: {}", path.display(), e)))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, ApiError> {
+    let certificates = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ApiError::ConfigError(format!("Invalid PEM in {}: {}", path.display(), e)))?;
+
+    if certificates.is_empty() {
+        return Err(ApiError::ConfigError(format!(
+            "No certificate found in {}",
+            path.display()
+        )));
+    }
+
+    Ok(certificates)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, ApiError> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| ApiError::ConfigError(format!("Invalid PEM in {}: {}", path.display(), e)))?
+        .ok_or_else(|| ApiError::ConfigError(format!("No private key found in {}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn ressource(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/ressources/tls")
+            .join(name)
+    }
+
+    #[test]
+    fn test_client_certificate_identities() {
+        let certificate = read_certificates(&ressource("client.pem"))
+            .unwrap()
+            .remove(0);
+        let client_certificate = ClientCertificate(certificate.to_vec());
+
+        assert_eq!(
+            client_certificate.identities().unwrap(),
+            vec![
+                "uri:spiffe://mesh.local/billing",
+                "dns:billing.mesh.local",
+                "cn:billing"
+            ]
+        );
+        assert_eq!(client_certificate.fingerprint().len(), 64);
+        assert!(ClientCertificate(vec![0, 1, 2]).identities().is_err());
+    }
+
+    #[test]
+    fn test_server_config() {
+        let mut config = TlsConfig {
+            cert_file: ressource("server.pem"),
+            key_file: ressource("server.key"),
+            client_ca_file: Some(ressource("ca.pem")),
+            require_client_certificate: true,
+        };
+        assert!(server_config(&config).is_ok());
+
+        config.client_ca_file = None;
+        assert!(server_config(&config).is_ok());
+
+        config.key_file = ressource("missing.key");
+        assert!(server_config(&config).is_err());
+    }
+}
@@ -1,5 +1,19 @@
 mod api_key;
+mod email;
+mod jwt;
 mod password;
+mod recovery_code;
+mod request_signature;
+mod signing_key_secret;
+mod token;
+mod totp;
 
 pub use api_key::ApiKey;
+pub use email::Email;
+pub use jwt::JwtKeys;
 pub use password::Password;
+pub use recovery_code::RecoveryCode;
+pub use request_signature::RequestSignature;
+pub use signing_key_secret::SigningKeySecret;
+pub use token::Token;
+pub use totp::Totp;
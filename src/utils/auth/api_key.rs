@@ -1,5 +1,13 @@
-use crate::errors::ApiError;
-use base64::{engine::general_purpose::STANDARD, Engine};
+use crate::{
+    config::{ApiKeyFormatConfig, ApiKeyHashConfig, ApiKeySecret},
+    errors::{ApiError, Violation},
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use crc32fast::hash as crc32;
+use hmac::{Hmac, Mac};
 use rand::random;
 use sha2::{Digest, Sha256};
 
@@ -8,65 +16,154 @@ pub struct ApiKey(String);
 
 impl ApiKey {
     // This constant helps us maintain consistency and makes changes easier
-    const PREFIX: &'static str = "ak_prod_";
+    const PREFIX: &'static str = "ak_";
+
+    // The version segment of the current format
+    const VERSION: &'static str = "v1";
+
+    // Keys issued before the format was versioned, all of them with the production prefix
+    const LEGACY_PREFIX: &'static str = "ak_prod_";
+
+    // Length of the URL-safe base64 encoding of 32 bytes, without padding
+    const SECRET_LENGTH: usize = 43;
+
+    // Length of the base62 encoded CRC32, enough for any 32 bits value
+    const CHECKSUM_LENGTH: usize = 6;
+
+    const BASE62: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
 
     // Create a new API key, with a custom input
-    pub fn new(api_key: impl Into<String>) -> Result<Self, ApiError> {
+    pub fn new(api_key: impl Into<String>, format: &ApiKeyFormatConfig) -> Result<Self, ApiError> {
         let api_key = ApiKey(api_key.into());
-        api_key.validate()?;
+        api_key.validate(format)?;
         Ok(api_key)
     }
 
-    // Helper method to check if a string matches our API key format
-    fn validate(&self) -> Result<&Self, ApiError> {
-        let validations = [
-            // The API key must start with the correct prefix
-            (
-                !self.0.starts_with(Self::PREFIX),
-                "API key must start with the correct prefix",
-            ),
-            // The base64 encoded data must be 32 bytes long
-            (
-                STANDARD
-                    .decode(&self.0[Self::PREFIX.len()..])
-                    .map_or(true, |decoded| decoded.len() != 32),
-                "API key must contain valid base64 encoded data of correct length",
-            ),
-        ];
+    // Helper method to check if a string matches our API key format,
+    // `ak_<environment>_v1_<secret><checksum>` or the legacy one during the migration window
+    fn validate(&self, format: &ApiKeyFormatConfig) -> Result<&Self, ApiError> {
+        let invalid = |message: &str| Err(ApiError::InvalidInput(message.to_string()));
+
+        let Some((environment, rest)) = self
+            .0
+            .strip_prefix(Self::PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+        else {
+            return invalid("API key must start with the correct prefix");
+        };
 
-        for (condition, message) in validations {
-            if condition {
-                return Err(ApiError::InvalidInput(message.to_string()));
+        // Legacy keys have no version segment, their secret is standard base64
+        let Some(body) = rest
+            .strip_prefix(Self::VERSION)
+            .and_then(|body| body.strip_prefix('_'))
+        else {
+            if !format.accept_legacy || !self.0.starts_with(Self::LEGACY_PREFIX) {
+                return invalid("API key must start with the correct prefix");
             }
+
+            return match STANDARD.decode(&self.0[Self::LEGACY_PREFIX.len()..]) {
+                Ok(decoded) if decoded.len() == 32 => Ok(self),
+                _ => invalid("API key must contain valid base64 encoded data of correct length"),
+            };
+        };
+
+        // Past the prefix, every broken rule is reported at once
+        let mut violations = Vec::new();
+
+        if environment != format.environment {
+            violations.push(Violation::new("API key was issued for another environment"));
         }
 
-        Ok(self)
+        if body.len() != Self::SECRET_LENGTH + Self::CHECKSUM_LENGTH || !body.is_ascii() {
+            violations.push(Violation::new(
+                "API key must contain valid base64 encoded data of correct length",
+            ));
+            return Err(ApiError::Validation(violations));
+        }
+
+        // The checksum tells typos apart without decoding anything
+        let (secret, checksum) = body.split_at(Self::SECRET_LENGTH);
+        let payload = &self.0[..self.0.len() - Self::CHECKSUM_LENGTH];
+        if checksum != Self::checksum(payload) {
+            violations.push(Violation::new("API key checksum does not match"));
+        }
+
+        if !matches!(URL_SAFE_NO_PAD.decode(secret), Ok(decoded) if decoded.len() == 32) {
+            violations.push(Violation::new(
+                "API key must contain valid base64 encoded data of correct length",
+            ));
+        }
+
+        match violations.is_empty() {
+            true => Ok(self),
+            false => Err(ApiError::Validation(violations)),
+        }
     }
 
     // Generate a new API key
-    pub fn generate() -> Self {
+    pub fn generate(format: &ApiKeyFormatConfig) -> Self {
         // A random 32 bytes long string
         let random_bytes: [u8; 32] = random();
 
-        // Encode the random bytes to a base64 string to ensure its url safe
-        let secret = STANDARD.encode(random_bytes);
+        // Encode the random bytes to a URL-safe base64 string, without padding
+        let secret = URL_SAFE_NO_PAD.encode(random_bytes);
 
-        // The API key is the concatenation of a prefix and the secret
-        let api_key = format!("{}{}", Self::PREFIX, secret);
+        // The checksum covers the prefix, environment, version and secret
+        let payload = format!(
+            "{}{}_{}_{}",
+            Self::PREFIX,
+            format.environment,
+            Self::VERSION,
+            secret
+        );
+        let api_key = format!("{}{}", payload, Self::checksum(&payload));
 
         Self(api_key)
     }
 
-    // Deterministic Hash function
-    pub fn hash(&self) -> String {
-        // Create a SHA-256 hasher
-        let mut hasher = Sha256::new();
+    // Base62 CRC32 of the key without its checksum, padded with zeros to a fixed length
+    fn checksum(payload: &str) -> String {
+        let mut value = crc32(payload.as_bytes());
+        let mut digits = [b'0'; Self::CHECKSUM_LENGTH];
 
-        // Update the hasher with the API key
-        hasher.update(self.0.as_bytes());
+        for digit in digits.iter_mut().rev() {
+            *digit = Self::BASE62[(value % 62) as usize];
+            value /= 62;
+        }
 
-        // Finalize the hasher and return the hash as a hexadecimal string
-        format!("{:x}", hasher.finalize())
+        String::from_utf8_lossy(&digits).into_owned()
+    }
+
+    // Version of the hashes made before they were keyed
+    pub const UNKEYED_HASH_VERSION: u8 = 0;
+
+    // Deterministic keyed hash, as stored in the database, so that a dump alone cannot be used to check keys
+    pub fn hash(&self, secret: &ApiKeySecret) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(self.0.as_bytes());
+
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+
+    // Plain SHA-256 of the keys stored before the hashes were keyed
+    fn unkeyed_hash(&self) -> String {
+        format!("{:x}", Sha256::digest(self.0.as_bytes()))
+    }
+
+    // Every hash the key may be stored under with its version, the current one first and the unkeyed one last
+    // while those are accepted
+    pub fn hashes(&self, config: &ApiKeyHashConfig) -> Vec<(u8, String)> {
+        let unkeyed = config
+            .accept_unkeyed
+            .then(|| (Self::UNKEYED_HASH_VERSION, self.unkeyed_hash()));
+
+        config
+            .secrets
+            .iter()
+            .map(|secret| (secret.version, self.hash(secret)))
+            .chain(unkeyed)
+            .collect()
     }
 
     // Method to get the string value
@@ -85,33 +182,68 @@ impl ApiKey {
 mod tests {
     use super::*;
 
+    fn secret(version: u8, secret: &str) -> ApiKeySecret {
+        ApiKeySecret {
+            version,
+            secret: secret.to_string(),
+        }
+    }
+
+    fn format(environment: &str, accept_legacy: bool) -> ApiKeyFormatConfig {
+        ApiKeyFormatConfig {
+            environment: environment.to_string(),
+            accept_legacy,
+        }
+    }
+
     #[test]
     fn api_key_generation_is_random() {
-        let api_key1 = ApiKey::generate();
-        let api_key2 = ApiKey::generate();
+        let api_key1 = ApiKey::generate(&format("prod", true));
+        let api_key2 = ApiKey::generate(&format("prod", true));
 
         assert_ne!(api_key1.as_str(), api_key2.as_str());
     }
 
     #[test]
-    fn api_key_has_constant_prefix() {
-        let api_key1 = ApiKey::generate();
-        let api_key2 = ApiKey::generate();
+    fn api_key_has_environment_prefix() {
+        let api_key1 = ApiKey::generate(&format("prod", true));
+        let api_key2 = ApiKey::generate(&format("test", true));
 
-        assert_eq!(&api_key1.as_str()[..ApiKey::PREFIX.len()], ApiKey::PREFIX);
-        assert_eq!(&api_key2.as_str()[..ApiKey::PREFIX.len()], ApiKey::PREFIX);
+        assert!(api_key1.as_str().starts_with("ak_prod_v1_"));
+        assert!(api_key2.as_str().starts_with("ak_test_v1_"));
+        assert_eq!(api_key1.as_str().len(), "ak_prod_v1_".len() + 43 + 6);
+    }
+
+    #[test]
+    fn api_key_is_url_safe() {
+        for _ in 0..100 {
+            let api_key = ApiKey::generate(&format("dev", true));
+
+            assert!(api_key
+                .as_str()
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        }
     }
 
     #[test]
     fn api_key_format_is_valid() {
+        let format = format("prod", true);
+
         // Correct key
-        let valid_key = ApiKey::new("ak_prod_YhssYXDTEhrycWESFjjwSorIkL79VzWreI7+NYPSLaU=");
+        let valid_key = ApiKey::new(
+            "ak_prod_v1_RK-bk1Sn-81h6K8CCrjvuKmB_IOH1pW5lulk-FMffzM4LwV1D",
+            &format,
+        );
         // Incorrect prefix
-        let invalid_key1 = ApiKey::new("invalid_key");
+        let invalid_key1 = ApiKey::new("invalid_key", &format);
         // Incorrect length
-        let invalid_key2 = ApiKey::new("ak_prod_invalid_key");
-        // Correct prefix and length but invalid base64 characters
-        let invalid_key3 = ApiKey::new("ak_prod_YhssYXDT@hrycWE$FjjwSorIkL79VzWreI7+NYPSLaU=");
+        let invalid_key2 = ApiKey::new("ak_prod_v1_invalid_key", &format);
+        // Correct prefix, length and checksum but invalid base64 characters
+        let invalid_key3 = ApiKey::new(
+            "ak_prod_v1_RK-bk1Sn-81h6K8CCrjvuKmB$IOH1pW5lulk-FMffzM4L18rvrc",
+            &format,
+        );
 
         assert!(valid_key.is_ok());
         assert!(invalid_key1.is_err());
@@ -119,19 +251,114 @@ mod tests {
         assert!(invalid_key3.is_err());
     }
 
+    #[test]
+    fn api_key_reports_every_violation() {
+        let api_key = ApiKey::generate(&format("test", true)).into_string();
+
+        // Another environment and a typo in the secret
+        let mut typo = api_key.replacen("ak_test_", "ak_prod_", 1).into_bytes();
+        typo[20] = if typo[20] == b'A' { b'B' } else { b'A' };
+        let typo = String::from_utf8(typo).unwrap();
+
+        match ApiKey::new(typo, &format("test", true)) {
+            Err(ApiError::Validation(violations)) => assert_eq!(
+                violations,
+                vec![
+                    Violation::new("API key was issued for another environment"),
+                    Violation::new("API key checksum does not match"),
+                ]
+            ),
+            other => panic!("Expected violations, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn api_key_checksum_catches_typos() {
+        let format = format("prod", true);
+        let api_key = ApiKey::generate(&format).into_string();
+
+        // Flip one character of the secret
+        let mut typo = api_key.clone().into_bytes();
+        typo[20] = if typo[20] == b'A' { b'B' } else { b'A' };
+        let typo = String::from_utf8(typo).unwrap();
+
+        assert!(ApiKey::new(&api_key, &format).is_ok());
+        assert!(ApiKey::new(typo, &format).is_err());
+    }
+
+    #[test]
+    fn api_key_is_bound_to_its_environment() {
+        let api_key = ApiKey::generate(&format("test", true)).into_string();
+
+        assert!(ApiKey::new(&api_key, &format("test", true)).is_ok());
+        assert!(ApiKey::new(&api_key, &format("prod", true)).is_err());
+    }
+
+    #[test]
+    fn legacy_api_key_is_accepted_during_migration() {
+        let legacy_key = "ak_prod_YhssYXDTEhrycWESFjjwSorIkL79VzWreI7+NYPSLaU=";
+
+        assert!(ApiKey::new(legacy_key, &format("prod", true)).is_ok());
+        assert!(ApiKey::new(legacy_key, &format("test", true)).is_ok());
+        assert!(ApiKey::new(legacy_key, &format("prod", false)).is_err());
+        assert!(ApiKey::new(
+            "ak_prod_YhssYXDT@hrycWE$FjjwSorIkL79VzWreI7+NYPSLaU=",
+            &format("prod", true)
+        )
+        .is_err());
+    }
+
     // That hash function must be deterministic as a standalone way to lookup in the db the corresponding user
     #[test]
     fn api_key_hash_is_deterministic() {
-        let api_key = ApiKey::new("ak_prod_YhssYXDTEhrycWESFjjwSorIkL79VzWreI7+NYPSLaU=").unwrap();
-        let hash1 = api_key.hash();
-        let hash2 = api_key.hash();
+        let api_key = ApiKey::new(
+            "ak_prod_YhssYXDTEhrycWESFjjwSorIkL79VzWreI7+NYPSLaU=",
+            &format("prod", true),
+        )
+        .unwrap();
+        let secret = secret(1, "a-server-side-secret-of-32-bytes!");
+        let hash1 = api_key.hash(&secret);
+        let hash2 = api_key.hash(&secret);
 
         assert_eq!(hash1, hash2);
+        assert_eq!(hash1.len(), 64);
+    }
+
+    #[test]
+    fn api_key_hash_depends_on_the_secret() {
+        let api_key = ApiKey::generate(&format("prod", true));
+        let mut config = ApiKeyHashConfig {
+            secrets: vec![
+                secret(2, "a-server-side-secret-of-32-bytes!"),
+                secret(1, "the-previous-secret-of-32-bytes!!"),
+            ],
+            accept_unkeyed: true,
+        };
+
+        let hashes = api_key.hashes(&config);
+        let versions: Vec<u8> = hashes.iter().map(|(version, _)| *version).collect();
+        assert_eq!(versions, vec![2, 1, ApiKey::UNKEYED_HASH_VERSION]);
+
+        // Neither secret gives the plain SHA-256 a dump could be checked against
+        assert_eq!(hashes[0].1, api_key.hash(config.current()));
+        assert_ne!(hashes[0].1, hashes[1].1);
+        assert_ne!(hashes[0].1, api_key.unkeyed_hash());
+        assert_eq!(hashes[2].1, api_key.unkeyed_hash());
+
+        // Once every key is rehashed, the unkeyed hash is no longer looked up
+        config.accept_unkeyed = false;
+        let versions: Vec<u8> = api_key
+            .hashes(&config)
+            .iter()
+            .map(|(version, _)| *version)
+            .collect();
+        assert_eq!(versions, vec![2, 1]);
     }
 
     #[test]
     fn validate_works_with_generated_keys() {
-        let api_key = ApiKey::generate();
-        assert!(api_key.validate().is_ok());
+        let format = format("dev", false);
+        let api_key = ApiKey::generate(&format);
+        assert!(api_key.validate(&format).is_ok());
     }
 }
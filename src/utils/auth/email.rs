@@ -0,0 +1,279 @@
+use crate::errors::{ApiError, Violation};
+use idna::domain_to_ascii_strict;
+use unicode_normalization::UnicodeNormalization;
+
+// Longest address that fits in the SMTP forward path, in octets
+const MAX_ADDRESS_LENGTH: usize = 254;
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+
+// Characters allowed in an unquoted local part besides letters and digits, as in the RFC 5322 `atext`
+const ATEXT_SPECIALS: &str = "!#$%&'*+-/=?^_`{|}~";
+
+// Email address of an account, an `addr-spec` without comments or domain literals
+#[derive(Debug, Clone)]
+pub struct Email {
+    // The address mail is sent to, with its domain in lowercase ASCII
+    address: String,
+    // The form accounts are told apart by, also lowercasing the local part
+    canonical: String,
+}
+
+impl Email {
+    // Normalize an email address given by a user. The case of the local part is kept unless told otherwise,
+    // since some mail servers still tell `John` and `john` apart
+    pub fn new(email: &str, lowercase_local_part: bool) -> Result<Self, ApiError> {
+        let email = email.trim();
+
+        let Some((local_part, domain)) = email.rsplit_once('@') else {
+            return Err(ApiError::Validation(vec![Violation::new(
+                "Email must contain an @",
+            )]));
+        };
+
+        let local_part: String = local_part.nfc().collect();
+        let mut violations = validate_local_part(&local_part);
+
+        // Internationalized domains are stored in their ASCII form, which every mail server understands
+        let domain = match domain_to_ascii_strict(domain) {
+            Ok(domain) => {
+                violations.extend(validate_domain(&domain));
+                domain
+            }
+            Err(_) => {
+                violations.push(Violation::new("Email domain is not a valid domain name"));
+                domain.to_lowercase()
+            }
+        };
+
+        let address = match lowercase_local_part {
+            true => format!("{}@{}", local_part.to_lowercase(), domain),
+            false => format!("{}@{}", local_part, domain),
+        };
+        if address.len() > MAX_ADDRESS_LENGTH {
+            violations.push(Violation::new(format!(
+                "Email must be at most {} bytes long",
+                MAX_ADDRESS_LENGTH
+            )));
+        }
+
+        match violations.is_empty() {
+            true => Ok(Email {
+                canonical: format!("{}@{}", local_part.to_lowercase(), domain),
+                address,
+            }),
+            false => Err(ApiError::Validation(violations)),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.address
+    }
+
+    // Two addresses belong to the same account when their canonical forms are equal
+    pub fn canonical(&self) -> &str {
+        &self.canonical
+    }
+}
+
+// Either a dot-atom or a quoted string, UTF-8 being allowed in both as in RFC 6532
+fn validate_local_part(local_part: &str) -> Vec<Violation> {
+    let mut violations = Vec::new();
+
+    if local_part.is_empty() {
+        violations.push(Violation::new("Email local part cannot be empty"));
+    }
+    if local_part.len() > MAX_LOCAL_PART_LENGTH {
+        violations.push(Violation::new(format!(
+            "Email local part must be at most {} bytes long",
+            MAX_LOCAL_PART_LENGTH
+        )));
+    }
+
+    let is_valid = match local_part
+        .strip_prefix('"')
+        .and_then(|quoted| quoted.strip_suffix('"'))
+    {
+        Some(quoted) => is_quoted_string(quoted),
+        None => local_part.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom.chars().all(|c| {
+                    c.is_ascii_alphanumeric() || ATEXT_SPECIALS.contains(c) || is_utf8_non_ascii(c)
+                })
+        }),
+    };
+    if !local_part.is_empty() && !is_valid {
+        violations.push(Violation::new(
+            "Email local part contains invalid characters",
+        ));
+    }
+
+    violations
+}
+
+// The content of a quoted local part, where only quotes and backslashes need escaping
+fn is_quoted_string(quoted: &str) -> bool {
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        let is_valid = match c {
+            '\\' => chars
+                .next()
+                .is_some_and(|c| c == ' ' || c.is_ascii_graphic()),
+            '"' => false,
+            _ => c == ' ' || c.is_ascii_graphic() || is_utf8_non_ascii(c),
+        };
+        if !is_valid {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn is_utf8_non_ascii(c: char) -> bool {
+    !c.is_ascii() && !c.is_control() && !c.is_whitespace()
+}
+
+// Rules on top of IDNA ones: mail is only delivered to fully qualified names, not to hosts or IP addresses
+fn validate_domain(domain: &str) -> Vec<Violation> {
+    let labels: Vec<&str> = domain.split('.').collect();
+
+    let is_qualified = labels.len() > 1
+        && labels.iter().all(|label| !label.is_empty())
+        && labels
+            .last()
+            .is_some_and(|tld| !tld.chars().all(|c| c.is_ascii_digit()));
+
+    match is_qualified {
+        true => Vec::new(),
+        false => vec![Violation::new(
+            "Email domain must be a fully qualified domain name",
+        )],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details(email: &str) -> Vec<String> {
+        match Email::new(email, false) {
+            Err(ApiError::Validation(violations)) => violations
+                .into_iter()
+                .map(|violation| violation.detail)
+                .collect(),
+            other => panic!("{} should be invalid, got {:?}", email, other),
+        }
+    }
+
+    #[test]
+    fn normalize_email() {
+        let email = Email::new("  John.Doe@Example.COM ", false).unwrap();
+        assert_eq!(email.as_str(), "John.Doe@example.com");
+        assert_eq!(email.canonical(), "john.doe@example.com");
+
+        let email = Email::new("John.Doe@Example.COM", true).unwrap();
+        assert_eq!(email.as_str(), "john.doe@example.com");
+        assert_eq!(email.canonical(), "john.doe@example.com");
+
+        // Case variants are the same account
+        assert_eq!(
+            Email::new("JOHN@x.com", false).unwrap().canonical(),
+            Email::new("john@X.COM", false).unwrap().canonical()
+        );
+    }
+
+    #[test]
+    fn internationalized_email() {
+        let email = Email::new("Jürgen@Bücher.de", false).unwrap();
+        assert_eq!(email.as_str(), "Jürgen@xn--bcher-kva.de");
+        assert_eq!(email.canonical(), "jürgen@xn--bcher-kva.de");
+
+        // Composed and decomposed forms of the same letter are the same address
+        assert_eq!(
+            Email::new("j\u{fc}rgen@x.com", false).unwrap().canonical(),
+            Email::new("ju\u{308}rgen@x.com", false)
+                .unwrap()
+                .canonical()
+        );
+    }
+
+    #[test]
+    fn accept_valid_emails() {
+        for email in [
+            "user+tag@example.com",
+            "first.last@sub.example.co.uk",
+            "o'brien@example.ie",
+            "\"john doe\"@example.com",
+            "\"quoted\\\"quote\"@example.com",
+            "\"at@sign\"@example.com",
+            "x@xn--bcher-kva.de",
+        ] {
+            assert!(
+                Email::new(email, false).is_ok(),
+                "{} should be valid",
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn reject_invalid_emails() {
+        assert_eq!(details("john.example.com"), ["Email must contain an @"]);
+        assert_eq!(
+            details("@example.com"),
+            ["Email local part cannot be empty"]
+        );
+        assert_eq!(
+            details("john..doe@example.com"),
+            ["Email local part contains invalid characters"]
+        );
+        assert_eq!(
+            details(".john@example.com"),
+            ["Email local part contains invalid characters"]
+        );
+        assert_eq!(
+            details("john doe@example.com"),
+            ["Email local part contains invalid characters"]
+        );
+        assert_eq!(
+            details("john@localhost"),
+            ["Email domain must be a fully qualified domain name"]
+        );
+        assert_eq!(
+            details("john@127.0.0.1"),
+            ["Email domain must be a fully qualified domain name"]
+        );
+        assert_eq!(
+            details("john@example..com"),
+            ["Email domain is not a valid domain name"]
+        );
+        assert_eq!(
+            details("john@exa_mple.com"),
+            ["Email domain is not a valid domain name"]
+        );
+
+        // Every broken rule is reported at once
+        assert_eq!(
+            details(&format!("{}@exa mple.com", "a".repeat(65))),
+            [
+                "Email local part must be at most 64 bytes long",
+                "Email domain is not a valid domain name"
+            ]
+        );
+        assert_eq!(
+            details(&format!("john@{}.com", vec!["a".repeat(60); 5].join("."))),
+            [
+                "Email domain is not a valid domain name",
+                "Email must be at most 254 bytes long"
+            ]
+        );
+        assert_eq!(
+            details(&format!(
+                "{}@{}.example.com",
+                "a".repeat(64),
+                vec!["a".repeat(60); 3].join(".")
+            )),
+            ["Email must be at most 254 bytes long"]
+        );
+    }
+}
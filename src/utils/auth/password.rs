@@ -1,120 +1,510 @@
-use crate::errors::ApiError;
+use crate::{
+    config::{PasswordHashConfig, PasswordPolicyConfig},
+    errors::{ApiError, Violation},
+};
+use actix_web::web::block;
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHash, SaltString},
-    Argon2, PasswordHasher, PasswordVerifier,
+    password_hash::{self, rand_core::OsRng, PasswordHash, SaltString},
+    Algorithm, Argon2, KeyId, Params, ParamsBuilder, PasswordHasher, PasswordVerifier, Version,
 };
+use bcrypt::HashParts;
+use pbkdf2::Pbkdf2;
+use scrypt::Scrypt;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::{fs::read_to_string, io::ErrorKind, path::Path};
+use unicode_normalization::UnicodeNormalization;
+
+// The algorithms stored hashes may use, all but Argon2 come from the systems users were imported from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scheme {
+    Argon2,
+    Bcrypt,
+    Scrypt,
+    Pbkdf2,
+}
+
+impl Scheme {
+    fn of(hash: &str) -> Result<Self, ApiError> {
+        // bcrypt predates the PHC string format and has its own
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            return Ok(Self::Bcrypt);
+        }
+
+        match PasswordHash::new(hash)?.algorithm.as_str() {
+            "argon2id" | "argon2i" | "argon2d" => Ok(Self::Argon2),
+            "scrypt" => Ok(Self::Scrypt),
+            "pbkdf2" | "pbkdf2-sha256" | "pbkdf2-sha512" => Ok(Self::Pbkdf2),
+            _ => Err(password_hash::Error::Algorithm.into()),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Password(String);
 
 impl Password {
-    pub fn new(password: impl Into<String>) -> Result<Self, ApiError> {
-        let password = Password(password.into());
-        password.validate()?;
+    pub async fn new(
+        password: impl Into<String>,
+        policy: &PasswordPolicyConfig,
+    ) -> Result<Self, ApiError> {
+        let password = Password(password.into().nfkc().collect());
+        password.validate(policy).await?;
         Ok(password)
     }
 
-    fn validate(&self) -> Result<&Self, ApiError> {
+    // A password only checked against a stored hash, which may predate the policy, e.g. for imported users.
+    // It is kept as typed, since the systems legacy hashes come from did not normalize passwords
+    pub fn existing(password: impl Into<String>) -> Self {
+        Password(password.into())
+    }
+
+    // The form hashes of this service are made of, so that a passphrase typed on another keyboard still matches
+    fn normalized(&self) -> String {
+        self.0.nfkc().collect()
+    }
+
+    async fn validate(&self, policy: &PasswordPolicyConfig) -> Result<&Self, ApiError> {
+        let length = self.0.chars().count();
+
         let validations = [
-            (self.0.is_empty(), "Password cannot be empty"),
+            (self.0.is_empty(), "Password cannot be empty".to_string()),
             (
-                !self.0.is_ascii(),
-                "Password must contain only ASCII characters",
+                !policy.allow_unicode && !self.0.is_ascii(),
+                "Password must contain only ASCII characters".to_string(),
             ),
             (
-                self.0.len() < 12,
-                "Password must be at least 12 characters long",
+                self.0.chars().any(char::is_control),
+                "Password cannot contain control characters".to_string(),
             ),
             (
-                self.0.len() > 64,
-                "Password must be at most 64 characters long",
+                length < policy.min_length,
+                format!(
+                    "Password must be at least {} characters long",
+                    policy.min_length
+                ),
             ),
             (
-                !self.0.chars().any(|c| c.is_ascii_lowercase()),
-                "Password must contain at least one lowercase letter",
+                length > policy.max_length,
+                format!(
+                    "Password must be at most {} characters long",
+                    policy.max_length
+                ),
             ),
             (
-                !self.0.chars().any(|c| c.is_ascii_uppercase()),
-                "Password must contain at least one uppercase letter",
+                policy.require_lowercase && !self.0.chars().any(char::is_lowercase),
+                "Password must contain at least one lowercase letter".to_string(),
             ),
             (
-                !self.0.chars().any(|c| c.is_ascii_digit()),
-                "Password must contain at least one digit",
+                policy.require_uppercase && !self.0.chars().any(char::is_uppercase),
+                "Password must contain at least one uppercase letter".to_string(),
             ),
             (
-                !self.0.chars().any(|c| !c.is_ascii_alphanumeric()),
-                "Password must contain at least one special character",
+                policy.require_digit && !self.0.chars().any(char::is_numeric),
+                "Password must contain at least one digit".to_string(),
+            ),
+            (
+                policy.require_special && !self.0.chars().any(|c| !c.is_alphanumeric()),
+                "Password must contain at least one special character".to_string(),
+            ),
+            (
+                self.entropy_bits() < f64::from(policy.min_entropy_bits),
+                "Password is too easy to guess".to_string(),
             ),
         ];
 
-        for (condition, message) in validations {
-            if condition {
-                return Err(ApiError::InvalidInput(message.to_string()));
+        // Every broken rule is reported at once
+        let mut violations: Vec<Violation> = validations
+            .into_iter()
+            .filter(|(condition, _)| *condition)
+            .map(|(_, message)| Violation::new(message))
+            .collect();
+
+        if let Some(dir) = &policy.breached_passwords_dir {
+            if self.is_breached(dir).await? {
+                violations.push(Violation::new("Password appears in a known data breach"));
+            }
+        }
+
+        match violations.is_empty() {
+            true => Ok(self),
+            false => Err(ApiError::Validation(violations)),
+        }
+    }
+
+    // Rough strength estimate: the size of the alphabet the characters are drawn from, raised to their count.
+    // Characters repeating or continuing a run of the previous one, as in `aaaa` or `1234`, do not count
+    fn entropy_bits(&self) -> f64 {
+        // Sizes of the lowercase, uppercase, digit, other ASCII and non-ASCII classes, the latter being a guess
+        let class_of = |c: char| match c {
+            'a'..='z' => 0,
+            'A'..='Z' => 1,
+            '0'..='9' => 2,
+            _ if c.is_ascii() => 3,
+            _ => 4,
+        };
+        let sizes = [26, 26, 10, 33, 100];
+
+        let mut used = [false; 5];
+        self.0.chars().for_each(|c| used[class_of(c)] = true);
+        let alphabet: u32 = (0..5).filter(|&i| used[i]).map(|i| sizes[i]).sum();
+
+        let mut characters = 0;
+        let mut previous: Option<char> = None;
+        for c in self.0.chars() {
+            if previous.is_none_or(|p| (c as i64 - p as i64).abs() > 1) {
+                characters += 1;
             }
+            previous = Some(c);
         }
 
-        Ok(self)
+        f64::from(characters) * f64::from(alphabet.max(1)).log2()
     }
 
-    pub fn hash(&self) -> Result<String, ApiError> {
+    // Look the SHA-1 up in the file of its 5 hex digits prefix, made of `<SUFFIX>:<COUNT>` lines.
+    // Lines with a zero count are padding of the range files and ignored. The file is read on the blocking
+    // thread pool, range files are too many to be loaded at startup
+    async fn is_breached(&self, dir: &Path) -> Result<bool, ApiError> {
+        let digest = format!("{:X}", Sha1::digest(self.0.as_bytes()));
+        let path = dir.join(format!("{}.txt", &digest[..5]));
+
+        let read = block(move || read_to_string(path)).await.map_err(|e| {
+            ApiError::ConfigError(format!("Failed to read the breached passwords: {}", e))
+        })?;
+        let suffix = &digest[5..];
+
+        let corpus = match read {
+            Ok(corpus) => corpus,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => {
+                return Err(ApiError::ConfigError(format!(
+                    "Failed to read the breached passwords: {}",
+                    e
+                )))
+            }
+        };
+
+        let is_breached = corpus.lines().any(|line| {
+            line.split_once(':').is_some_and(|(hash, count)| {
+                hash.trim().eq_ignore_ascii_case(suffix) && count.trim() != "0"
+            })
+        });
+
+        Ok(is_breached)
+    }
+
+    pub fn hash(&self, config: &PasswordHashConfig) -> Result<String, ApiError> {
         let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
+        let argon2 = argon2(config, params(config)?)?;
 
-        let hash = argon2.hash_password(self.0.as_bytes(), &salt)?.to_string();
+        let hash = argon2
+            .hash_password(self.normalized().as_bytes(), &salt)?
+            .to_string();
 
         Ok(hash)
     }
 
-    pub fn verify(&self, hash: &str) -> Result<bool, ApiError> {
+    // Verify with the algorithm and parameters stored in the hash, so that older hashes keep working.
+    // Legacy hashes are checked against the password as typed, they are replaced by a hash of its normalized form
+    // on the next rehash
+    pub fn verify(&self, hash: &str, config: &PasswordHashConfig) -> Result<bool, ApiError> {
+        let password = self.0.as_bytes();
+
+        let is_valid = match Scheme::of(hash)? {
+            Scheme::Argon2 => self.verify_argon2(hash, config)?,
+            Scheme::Bcrypt => bcrypt::verify(password, hash)
+                .map_err(|_| ApiError::HashError(password_hash::Error::PhcStringField))?,
+            Scheme::Scrypt => Scrypt
+                .verify_password(password, &PasswordHash::new(hash)?)
+                .is_ok(),
+            Scheme::Pbkdf2 => Pbkdf2
+                .verify_password(password, &PasswordHash::new(hash)?)
+                .is_ok(),
+        };
+
+        Ok(is_valid)
+    }
+
+    fn verify_argon2(&self, hash: &str, config: &PasswordHashConfig) -> Result<bool, ApiError> {
         let hash = PasswordHash::new(hash)?;
-        let argon2 = Argon2::default();
+        let hash_params = Params::try_from(&hash)?;
 
-        let is_valid = argon2.verify_password(self.0.as_bytes(), &hash).is_ok();
+        // Peppered hashes carry the key ID of their pepper, the others were hashed without one
+        let argon2 = match hash_params.keyid() {
+            [] => Argon2::new(Algorithm::Argon2id, Version::V0x13, hash_params),
+            keyid if Some(keyid) == pepper_id(config).as_ref().map(|id| &id[..]) => {
+                argon2(config, hash_params)?
+            }
+            _ => {
+                return Err(ApiError::ConfigError(
+                    "Password hash was peppered with another secret".to_string(),
+                ))
+            }
+        };
+
+        // Argon2 hashes made before passwords were normalized, or imported, match the password as typed
+        let normalized = self.normalized();
+        let is_valid = argon2.verify_password(normalized.as_bytes(), &hash).is_ok()
+            || (normalized != self.0 && argon2.verify_password(self.0.as_bytes(), &hash).is_ok());
 
         Ok(is_valid)
     }
+
+    // Spend the time a verification takes without any hash to compare with, e.g. for unknown users
+    pub fn verify_nothing(&self, config: &PasswordHashConfig) -> Result<(), ApiError> {
+        let salt = SaltString::from_b64("c2lnbi1pbi1wYWRkaW5n")?;
+        argon2(config, params(config)?)?.hash_password(self.normalized().as_bytes(), &salt)?;
+
+        Ok(())
+    }
+
+    // Whether a verified hash was computed with another algorithm or other parameters than the configured ones
+    pub fn needs_rehash(hash: &str, config: &PasswordHashConfig) -> Result<bool, ApiError> {
+        if Scheme::of(hash)? != Scheme::Argon2 {
+            return Ok(true);
+        }
+
+        let hash = PasswordHash::new(hash)?;
+        let (hash_params, params) = (Params::try_from(&hash)?, params(config)?);
+
+        let is_current = hash.algorithm == Algorithm::Argon2id.ident()
+            && hash.version == Some(Version::V0x13.into())
+            && hash_params.m_cost() == params.m_cost()
+            && hash_params.t_cost() == params.t_cost()
+            && hash_params.p_cost() == params.p_cost()
+            && hash_params.keyid() == params.keyid();
+
+        Ok(!is_current)
+    }
+
+    // Check that a hash produced elsewhere uses a supported algorithm and is well-formed
+    pub fn check_hash(hash: &str) -> Result<(), ApiError> {
+        match Scheme::of(hash)? {
+            Scheme::Bcrypt => hash
+                .parse::<HashParts>()
+                .map(|_| ())
+                .map_err(|_| ApiError::HashError(password_hash::Error::PhcStringField)),
+            Scheme::Argon2 => Params::try_from(&PasswordHash::new(hash)?)
+                .map(|_| ())
+                .map_err(ApiError::from),
+            Scheme::Scrypt | Scheme::Pbkdf2 => Ok(()),
+        }
+    }
+
+    // Check that the configured parameters can hash at all
+    pub fn check_config(config: &PasswordHashConfig) -> Result<(), ApiError> {
+        argon2(config, params(config)?).map(|_| ())
+    }
+}
+
+// The configured Argon2id parameters, tagged with the key ID of the pepper if any
+fn params(config: &PasswordHashConfig) -> Result<Params, ApiError> {
+    let mut builder = ParamsBuilder::new();
+    builder
+        .m_cost(config.memory_cost_kib)
+        .t_cost(config.iterations)
+        .p_cost(config.parallelism);
+
+    if let Some(pepper_id) = pepper_id(config) {
+        builder.keyid(KeyId::new(&pepper_id).map_err(password_hash::Error::from)?);
+    }
+
+    Ok(builder.build().map_err(password_hash::Error::from)?)
+}
+
+fn argon2(config: &PasswordHashConfig, params: Params) -> Result<Argon2<'_>, ApiError> {
+    match &config.pepper {
+        Some(pepper) => Ok(Argon2::new_with_secret(
+            pepper.as_bytes(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            params,
+        )
+        .map_err(password_hash::Error::from)?),
+        None => Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)),
+    }
+}
+
+// Identifies the pepper in the hashes without revealing it, so that a changed pepper is noticed
+fn pepper_id(config: &PasswordHashConfig) -> Option<[u8; 8]> {
+    config.pepper.as_ref().map(|pepper| {
+        let digest = Sha256::digest(pepper.as_bytes());
+        let mut id = [0; 8];
+        id.copy_from_slice(&digest[..8]);
+        id
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn create_password() {
-        let password = Password::new("Randompassword4!").unwrap().hash().unwrap();
+    fn config() -> PasswordHashConfig {
+        PasswordHashConfig {
+            memory_cost_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            pepper: None,
+        }
+    }
+
+    fn policy() -> PasswordPolicyConfig {
+        PasswordPolicyConfig {
+            min_length: 12,
+            max_length: 64,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_special: true,
+            allow_unicode: false,
+            min_entropy_bits: 30,
+            breached_passwords_dir: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn create_password() {
+        let password = Password::new("Randompassword4!", &policy())
+            .await
+            .unwrap()
+            .hash(&config())
+            .unwrap();
         println!("{:?}", password);
     }
 
-    #[test]
-    fn validate_password() {
+    #[actix_web::test]
+    async fn validate_password() {
         // Test empty password
-        assert!(Password::new("").is_err());
+        assert!(Password::new("", &policy()).await.is_err());
 
         // Test non-ASCII characters
-        assert!(Password::new("😀".repeat(12)).is_err());
+        assert!(Password::new("😀".repeat(12), &policy()).await.is_err());
 
         // Test exact boundary conditions
-        assert!(Password::new("a".repeat(11)).is_err());
-        assert!(Password::new("a".repeat(65)).is_err());
+        assert!(Password::new("a".repeat(11), &policy()).await.is_err());
+        assert!(Password::new("a".repeat(65), &policy()).await.is_err());
+
+        // Every broken rule is reported
+        match Password::new("abcdefghij", &policy()).await {
+            Err(ApiError::Validation(violations)) => assert_eq!(
+                violations,
+                vec![
+                    Violation::new("Password must be at least 12 characters long"),
+                    Violation::new("Password must contain at least one uppercase letter"),
+                    Violation::new("Password must contain at least one digit"),
+                    Violation::new("Password must contain at least one special character"),
+                    Violation::new("Password is too easy to guess"),
+                ]
+            ),
+            other => panic!("Expected violations, got {:?}", other),
+        }
 
         // Test required characters
-        assert!(Password::new("abcdefghij1!").is_err());
-        assert!(Password::new("ABCDEFGHIJ1!").is_err());
-        assert!(Password::new("Abcdefghijk!").is_err());
-        assert!(Password::new("Abcdefghijk1").is_err());
+        assert!(Password::new("abcdefghij1!", &policy()).await.is_err());
+        assert!(Password::new("ABCDEFGHIJ1!", &policy()).await.is_err());
+        assert!(Password::new("Abcdefghijk!", &policy()).await.is_err());
+        assert!(Password::new("Abcdefghijk1", &policy()).await.is_err());
 
         // Test valid passwords
-        assert!(Password::new("Abcd123!efgh").is_ok());
-        assert!(Password::new("P@ssw0rd585.").is_ok());
-        assert!(Password::new("Super$3cret!Pass").is_ok());
+        assert!(Password::new("Abcd123!efgh", &policy()).await.is_ok());
+        assert!(Password::new("P@ssw0rd585.", &policy()).await.is_ok());
+        assert!(Password::new("Super$3cret!Pass", &policy()).await.is_ok());
     }
 
-    #[test]
-    fn hash_password() {
-        let password = Password::new("Randompassword1!").unwrap();
+    #[actix_web::test]
+    async fn configure_password_policy() {
+        let passphrase_policy = PasswordPolicyConfig {
+            min_length: 16,
+            require_uppercase: false,
+            require_digit: false,
+            require_special: false,
+            allow_unicode: true,
+            ..policy()
+        };
+
+        // Unicode passphrases, without the character classes of shorter passwords
+        assert!(Password::new("ça va être très long", &passphrase_policy)
+            .await
+            .is_ok());
+        assert!(Password::new("пароль это фраза", &passphrase_policy)
+            .await
+            .is_ok());
+        assert!(Password::new("ça va être très long", &policy())
+            .await
+            .is_err());
+        assert!(Password::new("too short phrase", &passphrase_policy)
+            .await
+            .is_ok());
+        assert!(Password::new("way too short", &passphrase_policy)
+            .await
+            .is_err());
+        assert!(Password::new("new\nline in the phrase", &passphrase_policy)
+            .await
+            .is_err());
+
+        // The length counts characters, not bytes
+        let lenient = PasswordPolicyConfig {
+            require_lowercase: false,
+            min_entropy_bits: 0,
+            ..passphrase_policy
+        };
+        assert!(Password::new("😀".repeat(16), &lenient).await.is_ok());
+        assert!(Password::new("😀".repeat(15), &lenient).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn reject_guessable_passwords() {
+        // Runs and repetitions add little strength
+        assert!(Password::new("Aaaaaaaaaaa1!", &policy()).await.is_err());
+        assert!(Password::new("Abcdefghijk1!", &policy()).await.is_err());
+        assert!(Password::new("Aqwzsxedcrf1!", &policy()).await.is_ok());
+
+        let lenient = PasswordPolicyConfig {
+            min_entropy_bits: 0,
+            ..policy()
+        };
+        assert!(Password::new("Aaaaaaaaaaa1!", &lenient).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn reject_breached_passwords() {
+        let dir = std::env::temp_dir().join(format!("breached-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
 
-        let hash1 = password.hash().unwrap();
-        let hash2 = password.hash().unwrap();
+        // Range files list the hashes without their prefix, zero count lines are padding
+        let digest = format!("{:X}", Sha1::digest(b"Randompassword1!"));
+        let padding = format!("{:X}", Sha1::digest(b"Randompassword2!"));
+        std::fs::write(
+            dir.join(format!("{}.txt", &digest[..5])),
+            format!("{}:12\r\n{}:0\r\n", &digest[5..], &padding[5..]),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join(format!("{}.txt", &padding[..5])),
+            format!("{}:0\r\n", &padding[5..]),
+        )
+        .unwrap();
+
+        let screened = PasswordPolicyConfig {
+            breached_passwords_dir: Some(dir.clone()),
+            ..policy()
+        };
+        assert!(Password::new("Randompassword1!", &screened).await.is_err());
+        assert!(Password::new("Randompassword2!", &screened).await.is_ok());
+        assert!(Password::new("Randompassword3!", &screened).await.is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn hash_password() {
+        let password = Password::new("Randompassword1!", &policy()).await.unwrap();
+
+        let hash1 = password.hash(&config()).unwrap();
+        let hash2 = password.hash(&config()).unwrap();
 
         // Ensure that the password is not stored in plain text
         assert_ne!(password.0, hash1);
@@ -129,20 +519,142 @@ mod tests {
         assert_eq!(hash2.len(), EXPECTED_LENGTH);
     }
 
-    #[test]
-    fn verify_password() {
+    #[actix_web::test]
+    async fn verify_password() {
         // Test a matching password
-        let password = Password::new("Randompassword1!").unwrap();
-        let hash = password.hash().unwrap();
-        assert!(password.verify(&hash).unwrap());
+        let password = Password::new("Randompassword1!", &policy()).await.unwrap();
+        let hash = password.hash(&config()).unwrap();
+        assert!(password.verify(&hash, &config()).unwrap());
 
         // Test a non-matching password
-        let different_password = Password::new("Randompassword2!").unwrap();
-        let different_hash = different_password.hash().unwrap();
-        assert!(!password.verify(&different_hash).unwrap());
+        let different_password = Password::new("Randompassword2!", &policy()).await.unwrap();
+        let different_hash = different_password.hash(&config()).unwrap();
+        assert!(!password.verify(&different_hash, &config()).unwrap());
 
         // Test an invalid hash
         let invalid_hash = "random_string";
-        assert!(password.verify(&invalid_hash).is_err());
+        assert!(password.verify(invalid_hash, &config()).is_err());
+    }
+
+    #[test]
+    fn verify_nothing_uses_the_configured_costs() {
+        let password = Password::existing("Randompassword1!");
+        assert!(password.verify_nothing(&config()).is_ok());
+
+        let invalid = PasswordHashConfig {
+            memory_cost_kib: 1,
+            ..config()
+        };
+        assert!(password.verify_nothing(&invalid).is_err());
+    }
+
+    #[actix_web::test]
+    async fn rehash_on_changed_parameters() {
+        let password = Password::new("Randompassword1!", &policy()).await.unwrap();
+        let hash = password.hash(&config()).unwrap();
+        assert!(!Password::needs_rehash(&hash, &config()).unwrap());
+
+        // Hashes made with lower costs still verify, but are due for a rehash
+        let stronger = PasswordHashConfig {
+            memory_cost_kib: 32 * 1024,
+            iterations: 3,
+            ..config()
+        };
+        assert!(password.verify(&hash, &stronger).unwrap());
+        assert!(Password::needs_rehash(&hash, &stronger).unwrap());
+
+        let stronger_hash = password.hash(&stronger).unwrap();
+        assert!(stronger_hash.contains("m=32768,t=3,p=1"));
+        assert!(!Password::needs_rehash(&stronger_hash, &stronger).unwrap());
+    }
+
+    #[actix_web::test]
+    async fn verify_legacy_hashes() {
+        let password = Password::new("Randompassword1!", &policy()).await.unwrap();
+        let wrong_password = Password::new("Randompassword2!", &policy()).await.unwrap();
+
+        let bcrypt_hash = bcrypt::hash("Randompassword1!", 4).unwrap();
+        let scrypt_hash = Scrypt
+            .hash_password_customized(
+                b"Randompassword1!",
+                None,
+                None,
+                scrypt::Params::new(4, 8, 1, 32).unwrap(),
+                &SaltString::generate(&mut OsRng),
+            )
+            .unwrap()
+            .to_string();
+        let pbkdf2_hash = Pbkdf2
+            .hash_password_customized(
+                b"Randompassword1!",
+                Some(pbkdf2::Algorithm::Pbkdf2Sha256.ident()),
+                None,
+                pbkdf2::Params {
+                    rounds: 1000,
+                    output_length: 32,
+                },
+                &SaltString::generate(&mut OsRng),
+            )
+            .unwrap()
+            .to_string();
+
+        for hash in [bcrypt_hash, scrypt_hash, pbkdf2_hash] {
+            assert!(Password::check_hash(&hash).is_ok());
+            assert!(password.verify(&hash, &config()).unwrap());
+            assert!(!wrong_password.verify(&hash, &config()).unwrap());
+
+            // Legacy hashes are always upgraded to Argon2id
+            assert!(Password::needs_rehash(&hash, &config()).unwrap());
+        }
+
+        // Legacy systems did not normalize passwords, the ligature only matches as typed
+        let bcrypt_hash = bcrypt::hash("Random\u{fb01}le1!", 4).unwrap();
+        assert!(Password::existing("Random\u{fb01}le1!")
+            .verify(&bcrypt_hash, &config())
+            .unwrap());
+        assert!(!Password::existing("Randomfile1!")
+            .verify(&bcrypt_hash, &config())
+            .unwrap());
+
+        // Once rehashed, both forms match
+        let rehash = Password::existing("Random\u{fb01}le1!")
+            .hash(&config())
+            .unwrap();
+        assert!(Password::existing("Randomfile1!")
+            .verify(&rehash, &config())
+            .unwrap());
+        assert!(Password::existing("Random\u{fb01}le1!")
+            .verify(&rehash, &config())
+            .unwrap());
+
+        assert!(Password::check_hash("$md5$c2FsdA$aGFzaA").is_err());
+        assert!(Password::check_hash("$2b$12$tooshort").is_err());
+        assert!(Password::check_hash("plaintext").is_err());
+    }
+
+    #[actix_web::test]
+    async fn pepper_is_required_to_verify() {
+        let password = Password::new("Randompassword1!", &policy()).await.unwrap();
+        let peppered = PasswordHashConfig {
+            pepper: Some("server-side-pepper".to_string()),
+            ..config()
+        };
+
+        // Unpeppered hashes verify and get peppered on the next rehash
+        let hash = password.hash(&config()).unwrap();
+        assert!(password.verify(&hash, &peppered).unwrap());
+        assert!(Password::needs_rehash(&hash, &peppered).unwrap());
+
+        let peppered_hash = password.hash(&peppered).unwrap();
+        assert!(password.verify(&peppered_hash, &peppered).unwrap());
+        assert!(!Password::needs_rehash(&peppered_hash, &peppered).unwrap());
+
+        // Without the pepper the hash is useless
+        assert!(password.verify(&peppered_hash, &config()).is_err());
+        let other_pepper = PasswordHashConfig {
+            pepper: Some("another-pepper".to_string()),
+            ..config()
+        };
+        assert!(password.verify(&peppered_hash, &other_pepper).is_err());
     }
 }
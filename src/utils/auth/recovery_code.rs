@@ -0,0 +1,90 @@
+use crate::errors::ApiError;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha2::{Digest, Sha256};
+
+// One-time code that replaces a TOTP code when the authenticator is lost
+#[derive(Debug, Clone)]
+pub struct RecoveryCode(String);
+
+impl RecoveryCode {
+    const LENGTH: usize = 10;
+
+    // Create a recovery code from a user input, dashes and case are ignored to be forgiving with manual typing
+    pub fn new(recovery_code: impl Into<String>) -> Result<Self, ApiError> {
+        let recovery_code: String = recovery_code
+            .into()
+            .chars()
+            .filter(|c| *c != '-')
+            .collect::<String>()
+            .to_ascii_lowercase();
+
+        let recovery_code = RecoveryCode(recovery_code);
+        recovery_code.validate()?;
+        Ok(recovery_code)
+    }
+
+    fn validate(&self) -> Result<&Self, ApiError> {
+        match self.0.len() == Self::LENGTH && self.0.chars().all(|c| c.is_ascii_alphanumeric()) {
+            true => Ok(self),
+            false => Err(ApiError::InvalidInput(
+                "Invalid recovery code format".to_string(),
+            )),
+        }
+    }
+
+    // Generate a new random recovery code
+    pub fn generate() -> Self {
+        let recovery_code = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(Self::LENGTH)
+            .map(|c| (c as char).to_ascii_lowercase())
+            .collect();
+
+        Self(recovery_code)
+    }
+
+    // Deterministic hash function, recovery codes are only ever stored hashed
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.0.as_bytes());
+
+        format!("{:x}", hasher.finalize())
+    }
+
+    // The code as shown to the user, split in two halves for readability
+    pub fn into_string(self) -> String {
+        let (first, second) = self.0.split_at(Self::LENGTH / 2);
+        format!("{}-{}", first, second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_code_roundtrips_through_display_format() {
+        let recovery_code = RecoveryCode::generate();
+        let hash = recovery_code.hash();
+
+        let displayed = recovery_code.into_string();
+        assert_eq!(displayed.len(), 11);
+
+        // Users may type the code in upper case, with or without the dash
+        assert_eq!(RecoveryCode::new(&displayed).unwrap().hash(), hash);
+        assert_eq!(
+            RecoveryCode::new(displayed.replace('-', "").to_uppercase())
+                .unwrap()
+                .hash(),
+            hash
+        );
+    }
+
+    #[test]
+    fn recovery_code_format_is_valid() {
+        assert!(RecoveryCode::new("abcde-12345").is_ok());
+        assert!(RecoveryCode::new("abcde-1234").is_err());
+        assert!(RecoveryCode::new("abcde-1234!").is_err());
+        assert!(RecoveryCode::new("").is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::errors::ApiError;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+// The `Authorization` header of a request signed with a signing key, e.g.
+// `HMAC-SHA256 KeyId=<uuid>, Timestamp=<unix seconds>, Nonce=<nonce>, Signature=<base64>`
+#[derive(Debug, Clone)]
+pub struct RequestSignature {
+    pub key_id: String,
+    pub timestamp: i64,
+    pub nonce: String,
+    signature: Vec<u8>,
+}
+
+impl RequestSignature {
+    pub const SCHEME: &'static str = "HMAC-SHA256";
+
+    // Whether the header uses this scheme at all, other schemes are left to the other extractors
+    pub fn is_signature_header(header: &str) -> bool {
+        header
+            .split_once(' ')
+            .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case(Self::SCHEME))
+    }
+
+    // Parse the parameters of the header, every one of them is required
+    pub fn parse(header: &str) -> Result<Self, ApiError> {
+        let invalid = |message: &str| ApiError::Unauthorized(message.to_string());
+
+        let parameters = match header.split_once(' ') {
+            Some((scheme, parameters)) if scheme.eq_ignore_ascii_case(Self::SCHEME) => parameters,
+            _ => return Err(invalid("Unsupported authorization scheme")),
+        };
+
+        let (mut key_id, mut timestamp, mut nonce, mut signature) = (None, None, None, None);
+        for parameter in parameters.split(',') {
+            let (name, value) = parameter
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| invalid("Malformed signature parameter"))?;
+            let value = value.trim().to_string();
+
+            match name.trim() {
+                "KeyId" => key_id = Some(value),
+                "Timestamp" => timestamp = Some(value),
+                "Nonce" => nonce = Some(value),
+                "Signature" => signature = Some(value),
+                _ => return Err(invalid("Unknown signature parameter")),
+            }
+        }
+
+        let (Some(key_id), Some(timestamp), Some(nonce), Some(signature)) =
+            (key_id, timestamp, nonce, signature)
+        else {
+            return Err(invalid(
+                "KeyId, Timestamp, Nonce and Signature are required",
+            ));
+        };
+
+        let timestamp = timestamp
+            .parse()
+            .map_err(|_| invalid("Timestamp must be a number of seconds"))?;
+
+        // Nonces end up in memory, so they are kept short and plain
+        let is_valid_nonce = (16..=64).contains(&nonce.len())
+            && nonce
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_valid_nonce {
+            return Err(invalid(
+                "Nonce must be 16 to 64 letters, digits, dashes or underscores",
+            ));
+        }
+
+        let signature = STANDARD
+            .decode(signature)
+            .map_err(|_| invalid("Signature must be base64 encoded"))?;
+
+        Ok(Self {
+            key_id,
+            timestamp,
+            nonce,
+            signature,
+        })
+    }
+
+    // What the client signs, one field per line: method, path with query, timestamp, nonce and body hash
+    pub fn string_to_sign(
+        method: &str,
+        path_and_query: &str,
+        timestamp: i64,
+        nonce: &str,
+        body: &[u8],
+    ) -> String {
+        format!(
+            "{}\n{}\n{}\n{}\n{:x}",
+            method.to_uppercase(),
+            path_and_query,
+            timestamp,
+            nonce,
+            Sha256::digest(body)
+        )
+    }
+
+    // Check the signature in constant time
+    pub fn verify(&self, secret: &str, method: &str, path_and_query: &str, body: &[u8]) -> bool {
+        let string_to_sign =
+            Self::string_to_sign(method, path_and_query, self.timestamp, &self.nonce, body);
+
+        Self::mac(secret, &string_to_sign)
+            .verify_slice(&self.signature)
+            .is_ok()
+    }
+
+    fn mac(secret: &str, string_to_sign: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(string_to_sign.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "kOYoM5SeT-M3LqWdClwWZO0_E9Fogg63wGUxTuolMNQ";
+    const NONCE: &str = "0f8e7d6c5b4a39281706";
+
+    // Base64 HMAC-SHA256 of the string to sign, as computed by clients
+    fn sign(secret: &str, string_to_sign: &str) -> String {
+        STANDARD.encode(
+            RequestSignature::mac(secret, string_to_sign)
+                .finalize()
+                .into_bytes(),
+        )
+    }
+
+    fn header(signature: &str) -> String {
+        format!(
+            "HMAC-SHA256 KeyId=f1b3b3b3-1b3b-4b3b-8b3b-1b3b3b3b3b3b, Timestamp=1700000000, Nonce={}, Signature={}",
+            NONCE, signature
+        )
+    }
+
+    #[test]
+    fn signature_roundtrip() {
+        let string_to_sign = RequestSignature::string_to_sign(
+            "post",
+            "/v1/user/password?x=1",
+            1_700_000_000,
+            NONCE,
+            b"{}",
+        );
+        let signature = sign(SECRET, &string_to_sign);
+        let parsed = RequestSignature::parse(&header(&signature)).unwrap();
+
+        assert_eq!(parsed.key_id, "f1b3b3b3-1b3b-4b3b-8b3b-1b3b3b3b3b3b");
+        assert_eq!(parsed.timestamp, 1_700_000_000);
+        assert!(parsed.verify(SECRET, "POST", "/v1/user/password?x=1", b"{}"));
+
+        // Any change to the request breaks the signature
+        assert!(!parsed.verify(SECRET, "GET", "/v1/user/password?x=1", b"{}"));
+        assert!(!parsed.verify(SECRET, "POST", "/v1/user/password", b"{}"));
+        assert!(!parsed.verify(SECRET, "POST", "/v1/user/password?x=1", b"{ }"));
+        assert!(!parsed.verify("other", "POST", "/v1/user/password?x=1", b"{}"));
+    }
+
+    #[test]
+    fn header_format_is_validated() {
+        assert!(RequestSignature::is_signature_header(&header("c2ln")));
+        assert!(!RequestSignature::is_signature_header("Bearer ak_prod_"));
+
+        assert!(RequestSignature::parse(&header("c2ln")).is_ok());
+        assert!(RequestSignature::parse(&header("not base64!")).is_err());
+        assert!(RequestSignature::parse("HMAC-SHA256 KeyId=a, Timestamp=1").is_err());
+        assert!(RequestSignature::parse(
+            "HMAC-SHA256 KeyId=a, Timestamp=soon, Nonce=0f8e7d6c5b4a39281706, Signature=c2ln"
+        )
+        .is_err());
+        assert!(RequestSignature::parse(
+            "HMAC-SHA256 KeyId=a, Timestamp=1, Nonce=short, Signature=c2ln"
+        )
+        .is_err());
+    }
+}
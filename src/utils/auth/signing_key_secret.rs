@@ -0,0 +1,152 @@
+use crate::config::{EncryptionSecret, SigningKeyEncryptionConfig};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, Key, KeyInit, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::random;
+use sha2::{Digest, Sha256};
+
+// Secret of a signing key as stored, encrypted since the server needs the secret itself to check signatures
+#[derive(Debug, Clone)]
+pub struct SigningKeySecret {
+    // Version of the server secret it is encrypted with, 0 for secrets stored in clear
+    pub version: u8,
+    // Base64 of the nonce followed by the ciphertext
+    pub stored: String,
+}
+
+impl SigningKeySecret {
+    const NONCE_LENGTH: usize = 12;
+
+    // Encrypt a secret with the current server secret, bound to its key id so rows cannot be swapped
+    pub fn encrypt(secret: &str, key_id: &str, config: &SigningKeyEncryptionConfig) -> Self {
+        let current = config.current();
+        let nonce: [u8; Self::NONCE_LENGTH] = random();
+
+        let ciphertext = Self::cipher(current)
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: secret.as_bytes(),
+                    aad: key_id.as_bytes(),
+                },
+            )
+            .expect("AES-GCM encryption cannot fail on a short secret");
+
+        Self {
+            version: current.version,
+            stored: STANDARD.encode([nonce.as_slice(), &ciphertext].concat()),
+        }
+    }
+
+    // The secret in clear, None when its server secret is unknown or the ciphertext was tampered with
+    pub fn decrypt(&self, key_id: &str, config: &SigningKeyEncryptionConfig) -> Option<String> {
+        if self.version == 0 {
+            return Some(self.stored.clone());
+        }
+
+        let server_secret = config
+            .secrets
+            .iter()
+            .find(|secret| secret.version == self.version)?;
+        let decoded = STANDARD.decode(&self.stored).ok()?;
+        if decoded.len() < Self::NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = decoded.split_at(Self::NONCE_LENGTH);
+
+        let secret = Self::cipher(server_secret)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: key_id.as_bytes(),
+                },
+            )
+            .ok()?;
+
+        String::from_utf8(secret).ok()
+    }
+
+    // Whether the secret should be encrypted again with the current server secret
+    pub fn is_outdated(&self, config: &SigningKeyEncryptionConfig) -> bool {
+        self.version != config.current().version
+    }
+
+    // Server secrets are any string of at least 32 bytes, hashed into a key of the right size
+    fn cipher(server_secret: &EncryptionSecret) -> Aes256Gcm {
+        let key = Sha256::digest(server_secret.secret.as_bytes());
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(versions: &[u8]) -> SigningKeyEncryptionConfig {
+        SigningKeyEncryptionConfig {
+            secrets: versions
+                .iter()
+                .map(|&version| EncryptionSecret {
+                    version,
+                    secret: format!("signing-key-secret-number-{:06}", version),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn signing_key_secret_round_trip() {
+        let config = config(&[2, 1]);
+        let encrypted = SigningKeySecret::encrypt("secret", "key", &config);
+
+        assert_eq!(encrypted.version, 2);
+        assert!(!encrypted.stored.contains("secret"));
+        assert_eq!(encrypted.decrypt("key", &config).as_deref(), Some("secret"));
+
+        // The nonce is random, the same secret never gives the same ciphertext
+        let again = SigningKeySecret::encrypt("secret", "key", &config);
+        assert_ne!(encrypted.stored, again.stored);
+    }
+
+    #[test]
+    fn signing_key_secret_is_bound_to_its_key() {
+        let config = config(&[1]);
+        let encrypted = SigningKeySecret::encrypt("secret", "key", &config);
+        assert!(encrypted.decrypt("other-key", &config).is_none());
+
+        let mut tampered = STANDARD.decode(&encrypted.stored).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered = SigningKeySecret {
+            version: 1,
+            stored: STANDARD.encode(tampered),
+        };
+        assert!(tampered.decrypt("key", &config).is_none());
+    }
+
+    #[test]
+    fn signing_key_secret_rotation() {
+        let encrypted = SigningKeySecret::encrypt("secret", "key", &config(&[1]));
+
+        // Still readable after a rotation, but due to be encrypted again
+        let rotated = config(&[2, 1]);
+        assert!(encrypted.is_outdated(&rotated));
+        assert_eq!(
+            encrypted.decrypt("key", &rotated).as_deref(),
+            Some("secret")
+        );
+
+        // Unreadable once its server secret is dropped
+        assert!(encrypted.decrypt("key", &config(&[2])).is_none());
+
+        // Secrets stored before encryption are read as is
+        let plain = SigningKeySecret {
+            version: 0,
+            stored: "secret".to_string(),
+        };
+        assert!(plain.is_outdated(&rotated));
+        assert_eq!(plain.decrypt("key", &rotated).as_deref(), Some("secret"));
+    }
+}
@@ -0,0 +1,74 @@
+use crate::errors::ApiError;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::random;
+use sha2::{Digest, Sha256};
+
+// Single-use secret sent to the user out of band, e.g. by mail
+#[derive(Debug, Clone)]
+pub struct Token(String);
+
+impl Token {
+    // Create a token from a user input
+    pub fn new(token: impl Into<String>) -> Result<Self, ApiError> {
+        let token = Token(token.into());
+        token.validate()?;
+        Ok(token)
+    }
+
+    // The token must be the url safe base64 encoding of 32 bytes
+    fn validate(&self) -> Result<&Self, ApiError> {
+        match URL_SAFE_NO_PAD.decode(&self.0) {
+            Ok(decoded) if decoded.len() == 32 => Ok(self),
+            _ => Err(ApiError::InvalidInput(
+                "Token must contain valid base64 encoded data of correct length".to_string(),
+            )),
+        }
+    }
+
+    // Generate a new random token
+    pub fn generate() -> Self {
+        let random_bytes: [u8; 32] = random();
+
+        Self(URL_SAFE_NO_PAD.encode(random_bytes))
+    }
+
+    // Deterministic hash function, tokens are only ever stored hashed
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.0.as_bytes());
+
+        format!("{:x}", hasher.finalize())
+    }
+
+    // Method to get the string value
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_generation_is_random() {
+        assert_ne!(Token::generate().as_str(), Token::generate().as_str());
+    }
+
+    #[test]
+    fn token_format_is_valid() {
+        assert!(Token::new(Token::generate().as_str()).is_ok());
+        assert!(Token::new("").is_err());
+        assert!(Token::new("invalid_token").is_err());
+        // Standard base64 characters are not url safe
+        assert!(Token::new("YhssYXDTEhrycWESFjjwSorIkL79VzWreI7+NYPSLaU").is_err());
+    }
+
+    #[test]
+    fn token_hash_is_deterministic() {
+        let token = Token::generate();
+
+        assert_eq!(token.hash(), token.hash());
+        assert_eq!(token.hash().len(), 64);
+    }
+}
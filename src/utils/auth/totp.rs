@@ -0,0 +1,165 @@
+use crate::errors::ApiError;
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::random;
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+use url::Url;
+
+// RFC 6238 time-based one-time password generator, with the parameters every authenticator app supports
+#[derive(Debug, Clone)]
+pub struct Totp(Vec<u8>);
+
+impl Totp {
+    const ISSUER: &'static str = "http2sql";
+    const DIGITS: u32 = 6;
+    const PERIOD: i64 = 30;
+    // Number of periods before and after the current one that are still accepted, to allow for clock drift
+    const SKEW: i64 = 1;
+
+    // Generate a new random 160 bits secret, the size recommended by RFC 4226
+    pub fn generate() -> Self {
+        let secret: [u8; 20] = random();
+        Self(secret.to_vec())
+    }
+
+    // Load a secret stored as base32
+    pub fn from_base32(secret: &str) -> Result<Self, ApiError> {
+        let secret = BASE32_NOPAD
+            .decode(secret.as_bytes())
+            .map_err(|_| ApiError::InvalidInput("Invalid TOTP secret".to_string()))?;
+        Ok(Self(secret))
+    }
+
+    // The base32 encoding of the secret, as expected by authenticator apps
+    pub fn to_base32(&self) -> String {
+        BASE32_NOPAD.encode(&self.0)
+    }
+
+    // The otpauth URI shown to the user, usually as a QR code
+    pub fn uri(&self, account: &str) -> String {
+        let mut uri = Url::parse("otpauth://totp/").expect("The otpauth base URI is valid");
+        uri.set_path(&format!("{}:{}", Self::ISSUER, account));
+        uri.query_pairs_mut()
+            .append_pair("secret", &self.to_base32())
+            .append_pair("issuer", Self::ISSUER)
+            .append_pair("algorithm", "SHA1")
+            .append_pair("digits", &Self::DIGITS.to_string())
+            .append_pair("period", &Self::PERIOD.to_string());
+        uri.to_string()
+    }
+
+    // Check a code against the current time, returns the time step it belongs to. As required by RFC 6238,
+    // steps up to the last accepted one are refused so that an intercepted code cannot be replayed
+    pub fn verify(&self, code: &str, last_step: Option<i64>) -> Option<i64> {
+        self.verify_at(code, Utc::now().timestamp(), last_step)
+    }
+
+    fn verify_at(&self, code: &str, timestamp: i64, last_step: Option<i64>) -> Option<i64> {
+        if code.len() != Self::DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        // Every step of the window is compared in constant time, so that timing tells nothing about the match
+        let step = timestamp / Self::PERIOD;
+        let mut matched_step = None;
+        for step in (step - Self::SKEW..=step + Self::SKEW).filter(|step| *step >= 0) {
+            let expected = format!("{:06}", self.code_at(step as u64));
+            if bool::from(expected.as_bytes().ct_eq(code.as_bytes())) {
+                matched_step = Some(step);
+            }
+        }
+
+        matched_step.filter(|step| last_step.is_none_or(|last_step| *step > last_step))
+    }
+
+    // HOTP value of the counter as defined in RFC 4226
+    fn code_at(&self, counter: u64) -> u32 {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.0).expect("HMAC accepts keys of any length");
+        mac.update(&counter.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        // Dynamic truncation
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+
+        binary % 10u32.pow(Self::DIGITS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA1 seed used by the test vectors of RFC 6238
+    fn rfc_totp() -> Totp {
+        Totp(b"12345678901234567890".to_vec())
+    }
+
+    #[test]
+    fn totp_matches_rfc_test_vectors() {
+        let totp = rfc_totp();
+
+        // The RFC uses 8 digits, we only keep the last 6
+        assert_eq!(totp.verify_at("287082", 59, None), Some(1));
+        assert_eq!(totp.verify_at("081804", 1111111109, None), Some(37037036));
+        assert_eq!(totp.verify_at("050471", 1111111111, None), Some(37037037));
+        assert!(totp.verify_at("005924", 1234567890, None).is_some());
+        assert!(totp.verify_at("279037", 2000000000, None).is_some());
+    }
+
+    #[test]
+    fn totp_rejects_wrong_and_stale_codes() {
+        let totp = rfc_totp();
+
+        assert!(totp.verify_at("000000", 59, None).is_none());
+        assert!(totp.verify_at("not a code", 59, None).is_none());
+        // Only exactly 6 ASCII digits are codes
+        assert!(totp.verify_at(" 287082", 59, None).is_none());
+        assert!(totp.verify_at("+287082", 59, None).is_none());
+        assert!(totp.verify_at("0287082", 59, None).is_none());
+        assert!(totp.verify_at("２８７０８２", 59, None).is_none());
+        // One period of drift is tolerated, two are not
+        assert!(totp.verify_at("287082", 59 + 30, None).is_some());
+        assert!(totp.verify_at("287082", 59 + 60, None).is_none());
+    }
+
+    #[test]
+    fn totp_rejects_replayed_codes() {
+        let totp = rfc_totp();
+
+        // Accepted once, then refused for the rest of the window
+        let step = totp.verify_at("287082", 59, None).unwrap();
+        assert!(totp.verify_at("287082", 59, Some(step)).is_none());
+        assert!(totp.verify_at("287082", 59 + 30, Some(step)).is_none());
+        // Older steps are refused too, even if never used
+        assert!(totp.verify_at("287082", 59, Some(step + 1)).is_none());
+    }
+
+    #[test]
+    fn totp_secret_roundtrips_through_base32() {
+        let totp = Totp::generate();
+        let restored = Totp::from_base32(&totp.to_base32()).unwrap();
+
+        assert_eq!(totp.0, restored.0);
+        assert_eq!(totp.to_base32().len(), 32);
+        assert!(Totp::from_base32("not base32!").is_err());
+    }
+
+    #[test]
+    fn totp_uri_is_valid() {
+        let totp = rfc_totp();
+        let uri = totp.uri("john.doe@gmail.com");
+
+        assert!(uri.starts_with("otpauth://totp/http2sql:john.doe@gmail.com?"));
+        assert!(uri.contains("secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+        assert!(uri.contains("issuer=http2sql"));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::config::Config;
+use actix_web::{
+    dev::Payload,
+    http::header::{HeaderMap, USER_AGENT},
+    web::Data,
+    FromRequest, HttpRequest,
+};
+use ipnet::IpNet;
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+
+// IP address of the client. `X-Forwarded-For` hops are only followed through trusted proxies,
+// since clients can forge the header
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer_ip = req.peer_addr()?.ip();
+
+    let trusted_proxies = req
+        .app_data::<Data<Config>>()
+        .map(|config| config.trusted_proxies.as_slice())
+        .unwrap_or_default();
+
+    Some(forwarded_client_ip(peer_ip, req.headers(), trusted_proxies).to_string())
+}
+
+// Walk the forwarded hops from the nearest one, the client is the first hop that is not a trusted proxy
+fn forwarded_client_ip(peer_ip: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+
+    let mut client_ip = peer_ip;
+    if !is_trusted(&client_ip) {
+        return client_ip;
+    }
+
+    let hops: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    for hop in hops.into_iter().rev() {
+        // A malformed hop cannot be trusted, the last proxy that added a valid one is the best guess
+        let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+
+        client_ip = hop;
+        if !is_trusted(&client_ip) {
+            break;
+        }
+    }
+
+    client_ip
+}
+
+// Parse an IP network written in CIDR notation, a plain address is a network of its own
+pub fn parse_ip_network(value: &str) -> Option<IpNet> {
+    let value = value.trim();
+
+    value
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+// Where a request comes from, recorded in the audit log
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl FromRequest for RequestContext {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(512).collect());
+
+        ready(Ok(Self {
+            ip: client_ip(req),
+            user_agent,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn headers(forwarded_for: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in forwarded_for {
+            headers.append(
+                HeaderName::from_static("x-forwarded-for"),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn test_forwarded_client_ip() {
+        let proxies = vec![
+            parse_ip_network("10.0.0.0/8").unwrap(),
+            parse_ip_network("192.168.1.1").unwrap(),
+        ];
+        let ip = |value: &str| value.parse::<IpAddr>().unwrap();
+
+        // Forwarding headers of untrusted peers are ignored
+        let forwarded = headers(&["198.51.100.7"]);
+        assert_eq!(
+            forwarded_client_ip(ip("203.0.113.9"), &forwarded, &proxies),
+            ip("203.0.113.9")
+        );
+
+        // Trusted hops are skipped, a forged leftmost hop is not reached
+        let forwarded = headers(&["1.2.3.4, 198.51.100.7", "10.1.2.3"]);
+        assert_eq!(
+            forwarded_client_ip(ip("192.168.1.1"), &forwarded, &proxies),
+            ip("198.51.100.7")
+        );
+
+        // Malformed hops stop the walk
+        let forwarded = headers(&["198.51.100.7, unknown"]);
+        assert_eq!(
+            forwarded_client_ip(ip("10.0.0.1"), &forwarded, &proxies),
+            ip("10.0.0.1")
+        );
+
+        assert!(parse_ip_network("2001:db8::/32").is_some());
+        assert!(parse_ip_network("10.0.0.0/33").is_none());
+    }
+}
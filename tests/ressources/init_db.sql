@@ -1,41 +1,235 @@
 -- @block Init DB
 -- Delete the tables if they exists
+DROP TABLE IF EXISTS audit_log;
+DROP TABLE IF EXISTS lockout_events;
+DROP TABLE IF EXISTS sign_in_throttles;
+DROP TABLE IF EXISTS oidc_login_attempts;
+DROP TABLE IF EXISTS certificate_identities;
+DROP TABLE IF EXISTS user_identities;
+DROP TABLE IF EXISTS refresh_tokens;
+DROP TABLE IF EXISTS mfa_recovery_codes;
+DROP TABLE IF EXISTS mfa_challenges;
+DROP TABLE IF EXISTS email_verification_tokens;
+DROP TABLE IF EXISTS password_reset_tokens;
+DROP TABLE IF EXISTS signing_keys;
 DROP TABLE IF EXISTS api_keys;
+DROP TABLE IF EXISTS organization_invitations;
+DROP TABLE IF EXISTS organization_members;
+DROP TABLE IF EXISTS organizations;
 DROP TABLE IF EXISTS users;
 -- Create the schema
 CREATE TABLE users (
     uuid CHAR(36) NOT NULL UNIQUE,
-    email VARCHAR(255) NOT NULL UNIQUE,
-    password_hash CHAR(97) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    email_canonical VARCHAR(255) UNIQUE,
+    password_hash VARCHAR(255) NOT NULL,
     created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    verified_at DATETIME,
+    totp_secret CHAR(32),
+    totp_last_step BIGINT,
+    totp_enabled_at DATETIME,
+    role ENUM('user', 'admin') NOT NULL DEFAULT 'user',
+    deleted_at DATETIME,
     PRIMARY KEY (uuid)
 );
+CREATE TABLE organizations (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    name VARCHAR(255) NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (uuid)
+);
+CREATE TABLE organization_members (
+    organization_uuid CHAR(36) NOT NULL,
+    user_uuid CHAR(36) NOT NULL,
+    role ENUM('owner', 'admin', 'member') NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (organization_uuid, user_uuid),
+    FOREIGN KEY (organization_uuid) REFERENCES organizations(uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+);
+CREATE TABLE organization_invitations (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    organization_uuid CHAR(36) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    email_canonical VARCHAR(255),
+    role ENUM('admin', 'member') NOT NULL,
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    invited_by CHAR(36),
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL DEFAULT (DATE_ADD(CURRENT_TIMESTAMP, INTERVAL 7 DAY)),
+    accepted_at DATETIME,
+    PRIMARY KEY (uuid),
+    FOREIGN KEY (organization_uuid) REFERENCES organizations(uuid),
+    FOREIGN KEY (invited_by) REFERENCES users(uuid)
+);
 CREATE TABLE api_keys (
     uuid CHAR(36) NOT NULL UNIQUE,
     user_uuid CHAR(36) NOT NULL,
+    organization_uuid CHAR(36),
     api_key_hash CHAR(64) NOT NULL UNIQUE,
+    api_key_hash_version TINYINT UNSIGNED NOT NULL DEFAULT 0,
     created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
     last_used_at DATETIME ON UPDATE CURRENT_TIMESTAMP,
     expires_at DATETIME DEFAULT (DATE_ADD(CURRENT_TIMESTAMP, INTERVAL 7 DAY)),
+    allowed_cidrs JSON,
+    allowed_origins JSON,
+    PRIMARY KEY (uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+    FOREIGN KEY (organization_uuid) REFERENCES organizations(uuid)
+);
+CREATE TABLE signing_keys (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    user_uuid CHAR(36) NOT NULL,
+    organization_uuid CHAR(36),
+    secret VARCHAR(255) NOT NULL,
+    secret_version TINYINT UNSIGNED NOT NULL DEFAULT 0,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME DEFAULT (DATE_ADD(CURRENT_TIMESTAMP, INTERVAL 90 DAY)),
+    PRIMARY KEY (uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid),
+    FOREIGN KEY (organization_uuid) REFERENCES organizations(uuid)
+);
+CREATE TABLE password_reset_tokens (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    user_uuid CHAR(36) NOT NULL,
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL DEFAULT (DATE_ADD(CURRENT_TIMESTAMP, INTERVAL 1 HOUR)),
+    used_at DATETIME,
+    PRIMARY KEY (uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+);
+CREATE TABLE email_verification_tokens (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    user_uuid CHAR(36) NOT NULL,
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL DEFAULT (DATE_ADD(CURRENT_TIMESTAMP, INTERVAL 24 HOUR)),
+    used_at DATETIME,
     PRIMARY KEY (uuid),
     FOREIGN KEY (user_uuid) REFERENCES users(uuid)
 );
+CREATE TABLE mfa_challenges (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    user_uuid CHAR(36) NOT NULL,
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL DEFAULT (DATE_ADD(CURRENT_TIMESTAMP, INTERVAL 5 MINUTE)),
+    used_at DATETIME,
+    PRIMARY KEY (uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+);
+CREATE TABLE mfa_recovery_codes (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    user_uuid CHAR(36) NOT NULL,
+    code_hash CHAR(64) NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    used_at DATETIME,
+    PRIMARY KEY (uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+);
+CREATE TABLE refresh_tokens (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    user_uuid CHAR(36) NOT NULL,
+    family_uuid CHAR(36) NOT NULL,
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL,
+    used_at DATETIME,
+    revoked_at DATETIME,
+    PRIMARY KEY (uuid),
+    INDEX (family_uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+);
+CREATE TABLE user_identities (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    user_uuid CHAR(36) NOT NULL,
+    issuer VARCHAR(255) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (uuid),
+    UNIQUE (issuer, subject),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+);
+CREATE TABLE certificate_identities (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    user_uuid CHAR(36) NOT NULL,
+    identity VARCHAR(255) NOT NULL UNIQUE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (uuid),
+    FOREIGN KEY (user_uuid) REFERENCES users(uuid)
+);
+CREATE TABLE oidc_login_attempts (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    state_hash CHAR(64) NOT NULL UNIQUE,
+    nonce CHAR(43) NOT NULL,
+    code_verifier CHAR(43) NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL DEFAULT (DATE_ADD(CURRENT_TIMESTAMP, INTERVAL 10 MINUTE)),
+    used_at DATETIME,
+    PRIMARY KEY (uuid)
+);
+CREATE TABLE sign_in_throttles (
+    scope ENUM('account', 'ip') NOT NULL,
+    identifier VARCHAR(255) NOT NULL,
+    failed_attempts INT UNSIGNED NOT NULL DEFAULT 0,
+    last_failure_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    locked_until DATETIME,
+    PRIMARY KEY (scope, identifier)
+);
+CREATE TABLE lockout_events (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    scope ENUM('account', 'ip') NOT NULL,
+    identifier VARCHAR(255) NOT NULL,
+    event ENUM('locked', 'unlocked') NOT NULL,
+    locked_until DATETIME,
+    actor_uuid CHAR(36),
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (uuid),
+    FOREIGN KEY (actor_uuid) REFERENCES users(uuid)
+);
+-- Append-only, entries reference users without foreign keys so they outlive them
+CREATE TABLE audit_log (
+    uuid CHAR(36) NOT NULL UNIQUE,
+    actor_uuid CHAR(36),
+    api_key_uuid CHAR(36),
+    ip VARCHAR(45),
+    user_agent VARCHAR(512),
+    action VARCHAR(64) NOT NULL,
+    target VARCHAR(255),
+    diff JSON,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (uuid),
+    INDEX (actor_uuid, created_at),
+    INDEX (target, created_at),
+    INDEX (action, created_at),
+    INDEX (created_at)
+);
 -- Insert some mock users
-INSERT INTO users (uuid, email, password_hash)
+INSERT INTO users (uuid, email, email_canonical, password_hash, verified_at, role)
 VALUES (
         'b6cea585-0dc0-4887-8247-201f164a6d6a',
         'john.doe@gmail.com',
-        '$argon2id$v=19$m=19456,t=2,p=1$FMwa6Eb1swp7PpDLXToHog$9hNgeoBrX2WeoG/amPwGI/ekSAMukXawbK54b/NyiFQ'
+        'john.doe@gmail.com',
+        '$argon2id$v=19$m=19456,t=2,p=1$FMwa6Eb1swp7PpDLXToHog$9hNgeoBrX2WeoG/amPwGI/ekSAMukXawbK54b/NyiFQ',
+        CURRENT_TIMESTAMP,
+        'user'
     ),
     (
         'c8fdc92e-f72b-4fc6-b15d-ad006e063d83',
         'jane.doe@gmail.com',
-        '$argon2id$v=19$m=19456,t=2,p=1$j7RU52E7TKV6gvpUkTnfqw$HS1HlbL/bx/m6ZTQqkwy8oaylH64CGMnNwkNesxTrfw'
+        'jane.doe@gmail.com',
+        '$argon2id$v=19$m=19456,t=2,p=1$j7RU52E7TKV6gvpUkTnfqw$HS1HlbL/bx/m6ZTQqkwy8oaylH64CGMnNwkNesxTrfw',
+        CURRENT_TIMESTAMP,
+        'user'
     ),
     (
         '68a373e4-c8d7-4449-8e63-0f216a59fd0e',
         'alice.smith@gmail.com',
-        '$argon2id$v=19$m=19456,t=2,p=1$byHK//s8iG2imuuhqeuGbA$+oMywATyIdqejvsojcUR0m5ZV3izsy1KRFthYvFJDwU'
+        'alice.smith@gmail.com',
+        '$argon2id$v=19$m=19456,t=2,p=1$byHK//s8iG2imuuhqeuGbA$+oMywATyIdqejvsojcUR0m5ZV3izsy1KRFthYvFJDwU',
+        CURRENT_TIMESTAMP,
+        'admin'
     );
 -- Insert mock api keys
 INSERT INTO api_keys (uuid, user_uuid, api_key_hash)
@@ -43,4 +237,9 @@ VALUES (
         'f1b3b3b3-1b3b-4b3b-8b3b-1b3b3b3b3b3b',
         'b6cea585-0dc0-4887-8247-201f164a6d6a',
         '6371a4a2bfe90c3209f3ab1d8665c17fdce7f5314411e7676716f3a30f4e426f'
+    ),
+    (
+        '2d7c4e1a-9b8f-4c3d-a6e5-7f1b0c9d8e2a',
+        '68a373e4-c8d7-4449-8e63-0f216a59fd0e',
+        '2b3141d0853f3e37e3bd47cc00f1e95cf7e8194c9a73a8b2c863cd6433f48f7b'
     );
\ No newline at end of file